@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    fs::{File, read_dir},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use fastanvil::Region;
+use fastnbt::ByteArray;
+use serde::Deserialize;
+
+use crate::{
+    chunk::{AIR, Biomes, BlockState, BlockStates, ChunkStatus},
+    data::{
+        DH_DATA_FORMAT_VERSION, DH_SECTION_WIDTH, DHFullDataPoint, DHMappingEntry, DHSectionData,
+        DHSectionPos, RegionPos,
+    },
+    decompress::CompressionMode,
+};
+
+const DEFAULT_BIOME: &str = "minecraft:plains";
+const MAX_LIGHT: u8 = 15;
+/// Chunks per side of a DH section.
+const SECTION_CHUNKS: usize = DH_SECTION_WIDTH / 16;
+
+/// The parts of a chunk that DH data is built from.
+#[derive(Debug, Deserialize)]
+struct AnvilChunk {
+    #[serde(rename = "Status")]
+    status: String,
+    #[serde(default)]
+    sections: Vec<AnvilSection>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnvilSection {
+    #[serde(rename = "Y")]
+    y: i8,
+    block_states: Option<BlockStates>,
+    biomes: Option<Biomes>,
+    #[serde(rename = "BlockLight")]
+    block_light: Option<ByteArray>,
+    #[serde(rename = "SkyLight")]
+    sky_light: Option<ByteArray>,
+}
+
+impl AnvilSection {
+    /// Block and sky light at section-local `x`, `y`, `z`, `None` if the section has no light.
+    fn light(&self, x: u32, y: u32, z: u32) -> Option<(u8, u8)> {
+        let i = (y << 8 | z << 4 | x) as usize;
+        let nibble = |light: &ByteArray| (light[i >> 1] as u8 >> ((i & 1) * 4)) & 15;
+        Some((
+            nibble(self.block_light.as_ref()?),
+            nibble(self.sky_light.as_ref()?),
+        ))
+    }
+}
+
+/// World height that DH data is built for.
+#[derive(Debug, Clone, Copy)]
+pub struct AnvilOptions {
+    pub min_y: i32,
+    pub height: u32,
+    pub compression_mode: CompressionMode,
+}
+
+/// Lists the `r.<x>.<z>.mca` files in `dir`.
+pub fn region_files(dir: impl AsRef<Path>) -> Result<Vec<(RegionPos, PathBuf)>> {
+    let dir = dir.as_ref();
+    let mut regions = Vec::new();
+    for entry in read_dir(dir)
+        .with_context(|| format!("Failed to list region files in '{}'", dir.display()))?
+    {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let parts = name.split('.').collect::<Vec<_>>();
+        if let ["r", x, z, "mca"] = parts[..]
+            && let (Ok(x), Ok(z)) = (x.parse(), z.parse())
+        {
+            regions.push((RegionPos { x, z }, path));
+        }
+    }
+    regions.sort_by_key(|(pos, _)| (pos.x, pos.z));
+    Ok(regions)
+}
+
+/// The mapping and columns of one DH section being built.
+struct SectionBuilder {
+    mapping: Vec<DHMappingEntry>,
+    mapping_ids: HashMap<String, i32>,
+    data: Vec<Vec<DHFullDataPoint>>,
+}
+
+impl SectionBuilder {
+    fn new() -> Self {
+        SectionBuilder {
+            mapping: Vec::new(),
+            mapping_ids: HashMap::new(),
+            data: vec![Vec::new(); DH_SECTION_WIDTH * DH_SECTION_WIDTH],
+        }
+    }
+
+    fn mapping_id(&mut self, block: &BlockState, biome: &str) -> i32 {
+        let entry = DHMappingEntry {
+            biome: biome.to_string(),
+            block: (block.name != AIR).then(|| block.name.clone()),
+            block_state: block
+                .properties
+                .iter()
+                .flatten()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        };
+        let key = entry.serialize();
+        if let Some(id) = self.mapping_ids.get(&key) {
+            return *id;
+        }
+        let id = self.mapping.len() as i32;
+        self.mapping.push(entry);
+        self.mapping_ids.insert(key, id);
+        id
+    }
+}
+
+/// Builds the DH sections of the region file at `path`, one per section with a full chunk.
+///
+/// Every column is split into data points of the same block and biome from the top down,
+/// lit with the light of the block above them. Chunks that are not fully generated are
+/// left out, so their columns stay empty.
+pub fn read_region(
+    path: &Path,
+    region_pos: RegionPos,
+    options: &AnvilOptions,
+) -> Result<Vec<DHSectionData>> {
+    let mut region = Region::from_stream(File::open(path)?)
+        .with_context(|| format!("Failed to read region file '{}'", path.display()))?;
+    let region_section_pos = DHSectionPos::from(region_pos);
+    let mut builders = HashMap::<DHSectionPos, SectionBuilder>::new();
+    for chunk_data in region.iter() {
+        let chunk_data = chunk_data?;
+        let chunk = fastnbt::from_bytes::<AnvilChunk>(&chunk_data.data).with_context(|| {
+            format!(
+                "Failed to read chunk x:{} z:{} of '{}'",
+                chunk_data.x,
+                chunk_data.z,
+                path.display()
+            )
+        })?;
+        let status = chunk.status.trim_start_matches("minecraft:");
+        if ChunkStatus::Full.id().trim_start_matches("minecraft:") != status {
+            continue;
+        }
+        let section_pos = DHSectionPos {
+            x: region_section_pos.x + (chunk_data.x / SECTION_CHUNKS) as i32,
+            z: region_section_pos.z + (chunk_data.z / SECTION_CHUNKS) as i32,
+        };
+        let builder = builders
+            .entry(section_pos)
+            .or_insert_with(SectionBuilder::new);
+        let chunk_sections = chunk
+            .sections
+            .iter()
+            .map(|section| (section.y as i32, section))
+            .collect::<HashMap<_, _>>();
+        let chunk_x = (chunk_data.x % SECTION_CHUNKS) * 16;
+        let chunk_z = (chunk_data.z % SECTION_CHUNKS) * 16;
+        for x in 0..16 {
+            for z in 0..16 {
+                let data_col = read_column(&chunk_sections, x as u32, z as u32, builder, options);
+                builder.data[(chunk_x + x) * DH_SECTION_WIDTH + chunk_z + z] = data_col;
+            }
+        }
+    }
+    let mut sections = builders
+        .into_iter()
+        .map(|(pos, builder)| DHSectionData {
+            pos,
+            min_y: options.min_y,
+            data: builder.data,
+            mapping: builder.mapping,
+            data_format_version: DH_DATA_FORMAT_VERSION,
+            compression_mode: options.compression_mode,
+            last_modified: None,
+        })
+        .collect::<Vec<_>>();
+    sections.sort_by_key(|section| (section.pos.x, section.pos.z));
+    Ok(sections)
+}
+
+/// Data points of the column at chunk-local `x`, `z`, from the top down.
+/// `sections` are the sections of the chunk by section y.
+fn read_column(
+    sections: &HashMap<i32, &AnvilSection>,
+    x: u32,
+    z: u32,
+    builder: &mut SectionBuilder,
+    options: &AnvilOptions,
+) -> Vec<DHFullDataPoint> {
+    let air = BlockState {
+        name: AIR.to_string(),
+        properties: None,
+    };
+    let max_y = options.min_y + options.height as i32;
+
+    // Blocks, biomes and light from the top down. Missing biomes take the nearest biome above,
+    // or below at the top of the column.
+    let mut blocks = Vec::with_capacity(options.height as usize);
+    for y in (options.min_y..max_y).rev() {
+        let section = sections.get(&(y >> 4));
+        let local_y = (y & 15) as u32;
+        let block = section
+            .and_then(|section| section.block_states.as_ref())
+            .map_or(&air, |block_states| block_states.get(x, local_y, z));
+        let biome = section
+            .and_then(|section| section.biomes.as_ref())
+            .and_then(|biomes| biomes.get(x, local_y, z));
+        let light = section.and_then(|section| section.light(x, local_y, z));
+        blocks.push((block, biome, light));
+    }
+    let first_biome = blocks.iter().find_map(|(_, biome, _)| *biome);
+    let mut biome = first_biome.unwrap_or(DEFAULT_BIOME);
+    for (_, block_biome, _) in &mut blocks {
+        match block_biome {
+            Some(block_biome) => biome = block_biome,
+            None => *block_biome = Some(biome),
+        }
+    }
+
+    let mut data_col: Vec<DHFullDataPoint> = Vec::new();
+    let mut last_key = None;
+    // Light of the block above, estimated from the blocks above when the chunk has no light.
+    let mut light_above = (0, MAX_LIGHT);
+    let mut covered = false;
+    for (i, (block, biome, light)) in blocks.into_iter().enumerate() {
+        let y = max_y - 1 - i as i32;
+        let biome = biome.unwrap_or(DEFAULT_BIOME);
+        let key = Some((block, biome));
+        match data_col.last_mut() {
+            Some(data_point) if last_key == key => {
+                data_point.height += 1;
+                data_point.min_y = y - options.min_y;
+            }
+            _ => {
+                data_col.push(DHFullDataPoint {
+                    id: builder.mapping_id(block, biome),
+                    height: 1,
+                    min_y: y - options.min_y,
+                    block_light: light_above.0,
+                    sky_light: light_above.1,
+                });
+                last_key = key;
+            }
+        }
+        covered |= block.name != AIR;
+        light_above = light.unwrap_or((0, if covered { 0 } else { MAX_LIGHT }));
+    }
+    data_col
+}
@@ -1,71 +1,541 @@
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display, Formatter},
+    fs,
+    io::Read,
+    ops::Range,
+    path::Path,
+};
 
-use anyhow::{Result, bail};
-use fastnbt::{LongArray, Value};
+use anyhow::{Context, Result, bail, ensure};
+use clap::ValueEnum;
+use fastnbt::{ByteArray, LongArray, Value};
+use flate2::read::GzDecoder;
 use serde::{Deserialize, Serialize};
 
+use crate::packing::{Packing, bits_per_entry, pack_data, unpack_data};
+
 pub const AIR: &str = "minecraft:air";
+pub const WATER: &str = "minecraft:water";
+/// Blocks that are always filled with water, without a `waterlogged` state.
+pub const WATER_PLANTS: [&str; 5] = [
+    "minecraft:seagrass",
+    "minecraft:tall_seagrass",
+    "minecraft:kelp",
+    "minecraft:kelp_plant",
+    "minecraft:bubble_column",
+];
+/// Blocks without collision, recognized by name. Together with `NON_SOLID_SUFFIXES` this
+/// covers the vanilla blocks commonly found at the surface, for the heightmaps.
+const NON_SOLID_BLOCKS: [&str; 44] = [
+    "minecraft:lava",
+    "minecraft:short_grass",
+    "minecraft:grass",
+    "minecraft:tall_grass",
+    "minecraft:fern",
+    "minecraft:large_fern",
+    "minecraft:dead_bush",
+    "minecraft:vine",
+    "minecraft:glow_lichen",
+    "minecraft:snow",
+    "minecraft:sugar_cane",
+    "minecraft:dandelion",
+    "minecraft:poppy",
+    "minecraft:blue_orchid",
+    "minecraft:allium",
+    "minecraft:azure_bluet",
+    "minecraft:oxeye_daisy",
+    "minecraft:cornflower",
+    "minecraft:lily_of_the_valley",
+    "minecraft:torchflower",
+    "minecraft:sunflower",
+    "minecraft:lilac",
+    "minecraft:rose_bush",
+    "minecraft:peony",
+    "minecraft:pink_petals",
+    "minecraft:sweet_berry_bush",
+    "minecraft:cobweb",
+    "minecraft:hanging_roots",
+    "minecraft:cave_vines",
+    "minecraft:cave_vines_plant",
+    "minecraft:weeping_vines",
+    "minecraft:weeping_vines_plant",
+    "minecraft:twisting_vines",
+    "minecraft:twisting_vines_plant",
+    "minecraft:crimson_roots",
+    "minecraft:warped_roots",
+    "minecraft:nether_sprouts",
+    "minecraft:brown_mushroom",
+    "minecraft:red_mushroom",
+    "minecraft:wheat",
+    "minecraft:carrots",
+    "minecraft:potatoes",
+    "minecraft:beetroots",
+    "minecraft:redstone_wire",
+];
+const NON_SOLID_SUFFIXES: [&str; 6] = ["_sapling", "_tulip", "torch", "_sign", "_button", "rail"];
+const DEFAULT_BIOME: &str = "minecraft:plains";
+
+const SECTION_BLOCKS: usize = 16 * 16 * 16;
+const BIOME_CELLS: usize = 4 * 4 * 4;
+pub const MAX_LIGHT: u8 = 15;
+const UNSET_BIOME: u16 = u16::MAX;
+
+/// How the biome of a 4x4x4 cell is chosen from the biomes of its blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum BiomeSampling {
+    /// The most common biome in the cell.
+    #[default]
+    Majority,
+    /// The biome at the center of the cell.
+    Center,
+}
+
+/// Data version and height of the chunks to write.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkFormat {
+    pub data_version: i32,
+    /// Lowest block height, a multiple of 16.
+    pub min_y: i32,
+    /// Number of 16 block high sections.
+    pub section_count: usize,
+}
+
+impl ChunkFormat {
+    /// The first data version with the 1.18 chunk layout, without the `Level` compound.
+    const MIN_DATA_VERSION: i32 = 2844;
+
+    pub fn new(data_version: i32, min_y: i32, height: u32) -> Result<ChunkFormat> {
+        ensure!(
+            Self::MIN_DATA_VERSION <= data_version,
+            "Data version {data_version} is not supported, the chunk layout of data version {} (1.18) or later is written",
+            Self::MIN_DATA_VERSION
+        );
+        ensure!(
+            min_y % 16 == 0 && height.is_multiple_of(16) && height > 0,
+            "World min y {min_y} and height {height} must be multiples of 16"
+        );
+        Ok(ChunkFormat {
+            data_version,
+            min_y,
+            section_count: height as usize / 16,
+        })
+    }
+}
+
+impl Default for ChunkFormat {
+    /// Minecraft 1.21.6 overworld.
+    fn default() -> Self {
+        ChunkFormat {
+            data_version: 4435,
+            min_y: -64,
+            section_count: 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Structures {
+    #[serde(rename = "References")]
+    pub references: HashMap<String, Value>,
+    pub starts: HashMap<String, Value>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chunk {
+    #[serde(rename = "DataVersion")]
+    pub data_version: i32,
     #[serde(rename = "xPos")]
     pub x: i32,
+    #[serde(rename = "yPos")]
+    pub y: i32,
     #[serde(rename = "zPos")]
     pub z: i32,
     #[serde(rename = "Status")]
     pub status: String,
+    #[serde(rename = "LastUpdate", default)]
+    pub last_update: i64,
+    #[serde(rename = "isLightOn", default)]
+    pub is_light_on: bool,
+    #[serde(rename = "PostProcessing", default)]
+    pub post_processing: Vec<Vec<i16>>,
+    #[serde(rename = "InhabitedTime", default)]
+    pub inhabited_time: i64,
     pub sections: Vec<Section>,
+    #[serde(default)]
+    pub block_entities: Vec<Value>,
+    #[serde(default)]
+    pub block_ticks: Vec<ScheduledTick>,
+    #[serde(default)]
+    pub fluid_ticks: Vec<ScheduledTick>,
+    #[serde(default)]
+    pub structures: Structures,
 
     #[serde(flatten)]
     other: HashMap<String, Value>,
 }
 
 impl Chunk {
+    /// An empty chunk of air at chunk position `x`, `z`.
+    pub fn new(x: i32, z: i32, format: &ChunkFormat) -> Chunk {
+        let min_section_y = format.min_y >> 4;
+        Chunk {
+            data_version: format.data_version,
+            x,
+            y: min_section_y,
+            z,
+            status: ChunkStatus::Empty.id().to_string(),
+            last_update: 0,
+            is_light_on: false,
+            post_processing: vec![Vec::new(); format.section_count],
+            inhabited_time: 0,
+            sections: (0..format.section_count)
+                .map(|i| Section::new((min_section_y + i as i32) as i8))
+                .collect(),
+            block_entities: Vec::new(),
+            block_ticks: Vec::new(),
+            fluid_ticks: Vec::new(),
+            structures: Structures::default(),
+            other: HashMap::new(),
+        }
+    }
+
+    /// Reads a chunk to use in place of `Chunk::new`, from an NBT file that may be gzip compressed.
+    /// Its tags are carried into every generated chunk, but its biomes are dropped so that
+    /// they come from the DH data alone. Its block entities, scheduled ticks and structures
+    /// are dropped too, as they refer to positions in the template's own chunk.
+    pub fn load_template(file: impl AsRef<Path>, format: &ChunkFormat) -> Result<Chunk> {
+        let file = file.as_ref();
+        let bytes = fs::read(file)?;
+        let bytes = if bytes.starts_with(&[0x1f, 0x8b]) {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed)?;
+            decompressed
+        } else {
+            bytes
+        };
+        let mut chunk = fastnbt::from_bytes::<Chunk>(&bytes)
+            .with_context(|| format!("Failed to read chunk template '{}'", file.display()))?;
+        ensure!(
+            chunk.data_version == format.data_version,
+            "Chunk template '{}' has DataVersion {}, but {} is being written",
+            file.display(),
+            chunk.data_version,
+            format.data_version
+        );
+        let min_section_y = format.min_y >> 4;
+        ensure!(
+            chunk.y == min_section_y && chunk.sections.len() == format.section_count,
+            "Chunk template '{}' has {} sections from section y {}, but the world has {} sections from section y {min_section_y}",
+            file.display(),
+            chunk.sections.len(),
+            chunk.y,
+            format.section_count
+        );
+        for (i, section) in chunk.sections.iter_mut().enumerate() {
+            ensure!(
+                section.y as i32 == min_section_y + i as i32,
+                "Chunk template '{}' has section y {} at index {i}, expected {}",
+                file.display(),
+                section.y,
+                min_section_y + i as i32
+            );
+            section.biomes = Biomes::new();
+        }
+        chunk.block_entities.clear();
+        chunk.block_ticks.clear();
+        chunk.fluid_ticks.clear();
+        chunk.structures = Structures::default();
+        Ok(chunk)
+    }
+
     pub fn set_chunk_pos(&mut self, x: i32, z: i32) {
         self.x = x;
         self.z = z;
     }
 
-    pub fn set_status(&mut self, status: String) {
-        self.status = status;
+    /// Sets the generation status together with the flags the game expects for it.
+    ///
+    /// Light is marked as computed from the `light` status on, so the light must then be
+    /// filled with `fill_light`. Heightmaps are dropped until `update_heightmaps` is called.
+    pub fn set_status(&mut self, status: ChunkStatus) {
+        self.status = status.id().to_string();
+        self.is_light_on = status.has_light();
+        self.post_processing = vec![Vec::new(); self.sections.len()];
+        self.other.remove("Heightmaps");
+        for section in &mut self.sections {
+            section.block_light = None;
+            section.sky_light = None;
+        }
+    }
+
+    /// Computes the heightmaps the game keeps for chunks at `status` from the blocks.
+    /// Which blocks stop motion is told from their names, see `BlockState::blocks_motion`.
+    pub fn update_heightmaps(&mut self, status: ChunkStatus) {
+        let heightmaps = status.heightmaps();
+        if heightmaps.is_empty() {
+            self.other.remove("Heightmaps");
+            return;
+        }
+        // The heights of each heightmap, the block height above its top block minus the min y.
+        let mut heights = vec![[0u16; 256]; heightmaps.len()];
+        for (section_i, section) in self.sections.iter().enumerate().rev() {
+            let palette = &section.block_states.palette;
+            let matches = palette
+                .iter()
+                .map(|block| {
+                    heightmaps
+                        .iter()
+                        .map(|heightmap| heightmap.matches(block))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            for xz in 0..256 {
+                for local_y in (0..16).rev() {
+                    let block = section.block_states.data[local_y << 8 | xz] as usize;
+                    for (heightmap, height) in heights.iter_mut().enumerate() {
+                        if height[xz] == 0 && matches[block][heightmap] {
+                            height[xz] = (section_i * 16 + local_y + 1) as u16;
+                        }
+                    }
+                }
+            }
+        }
+        let bits = bits_per_entry(self.sections.len() * 16 + 1, 1);
+        let compound = heightmaps
+            .iter()
+            .zip(heights)
+            .map(|(heightmap, height)| {
+                let packed = pack_data(&height, bits, Packing::Padded);
+                (
+                    heightmap.name().to_string(),
+                    Value::LongArray(LongArray::new(packed)),
+                )
+            })
+            .collect();
+        self.other
+            .insert("Heightmaps".to_string(), Value::Compound(compound));
+    }
+
+    /// Sets the block and sky light at `x`, `z` over the block height range `ys`.
+    /// The parts of `ys` outside the chunk height are ignored.
+    pub fn fill_light(&mut self, x: u32, z: u32, ys: Range<i32>, block_light: u8, sky_light: u8) {
+        let Some(first_section) = self.sections.first() else {
+            return;
+        };
+        let chunk_min_y = first_section.y as i32 * 16;
+        for y in
+            ys.start.max(chunk_min_y)..ys.end.min(chunk_min_y + self.sections.len() as i32 * 16)
+        {
+            let section = &mut self.sections[((y - chunk_min_y) >> 4) as usize];
+            let i = ((y & 0xf) as u32) << 8 | z << 4 | x;
+            set_nibble(&mut section.block_light, i as usize, block_light);
+            set_nibble(&mut section.sky_light, i as usize, sky_light);
+        }
+    }
+
+    /// Schedules a tick for `fluid` at `x`, `z` in this chunk and the block height `y`.
+    pub fn schedule_fluid_tick(&mut self, x: u32, y: i32, z: u32, fluid: &str) {
+        self.fluid_ticks.push(ScheduledTick {
+            id: fluid.to_string(),
+            x: self.x * 16 + x as i32,
+            y,
+            z: self.z * 16 + z as i32,
+            delay: 0,
+            priority: 0,
+        });
     }
 
-    pub fn set_block_biome(
+    pub fn set_biome_sampling(&mut self, sampling: BiomeSampling) {
+        for section in &mut self.sections {
+            section.biomes.sampling = sampling;
+        }
+    }
+
+    /// Fills the blocks at `x`, `z` over the block height range `ys` with one block and biome,
+    /// given as ids of `palette`. A chunk must always be filled from the same `palette`.
+    /// The parts of `ys` outside the chunk height are ignored.
+    pub fn fill_column(
         &mut self,
         x: u32,
-        y: i32,
         z: u32,
-        block: BlockState,
-        biome: String,
+        ys: Range<i32>,
+        block: u16,
+        biome: u16,
+        palette: &ChunkPalette,
     ) -> Result<()> {
-        if 16 <= x || 16 <= z || !(-64..320).contains(&y) {
-            bail!("x or y or z is out of bounds x:{x} y:{y} z:{z}");
+        if 16 <= x || 16 <= z {
+            bail!("x or z is out of bounds x:{x} z:{z}");
+        }
+        let Some(first_section) = self.sections.first() else {
+            return Ok(());
+        };
+        let chunk_min_y = first_section.y as i32 * 16;
+        let chunk_max_y = chunk_min_y + self.sections.len() as i32 * 16;
+        let ys = ys.start.max(chunk_min_y)..ys.end.min(chunk_max_y);
+        let mut y = ys.start;
+        while y < ys.end {
+            let section_idx = ((y - chunk_min_y) >> 4) as usize;
+            let section_end = (y & !0xf) + 16;
+            let section_ys = (y & 0xf) as u32..(ys.end.min(section_end) - (y & !0xf)) as u32;
+            self.sections[section_idx].fill_column(x, z, section_ys, block, biome, palette);
+            y = section_end;
         }
-        let section = &mut self.sections[((y as f32 / 16.).floor() + 4.) as usize];
+        Ok(())
+    }
+}
 
-        let y = (y & 0xf) as u32;
+/// Generation status of a written chunk. The game runs the remaining steps when it loads the chunk.
+/// Ordered like the generation steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum ChunkStatus {
+    Empty,
+    StructureStarts,
+    StructureReferences,
+    Biomes,
+    Noise,
+    Surface,
+    Carvers,
+    Features,
+    InitializeLight,
+    Light,
+    Spawn,
+    Full,
+}
 
-        let id = if let Some(id) = section.block_states.rev_palette.get(&block) {
-            *id
-        } else {
-            let id = section.block_states.palette.len() as u16;
-            section.block_states.palette.push(block.clone());
-            section.block_states.rev_palette.insert(block, id);
-            id
-        };
-        section.block_states.data[(y << 8 | z << 4 | x) as usize] = id;
+impl ChunkStatus {
+    pub fn id(&self) -> &'static str {
+        match self {
+            ChunkStatus::Empty => "minecraft:empty",
+            ChunkStatus::StructureStarts => "minecraft:structure_starts",
+            ChunkStatus::StructureReferences => "minecraft:structure_references",
+            ChunkStatus::Biomes => "minecraft:biomes",
+            ChunkStatus::Noise => "minecraft:noise",
+            ChunkStatus::Surface => "minecraft:surface",
+            ChunkStatus::Carvers => "minecraft:carvers",
+            ChunkStatus::Features => "minecraft:features",
+            ChunkStatus::InitializeLight => "minecraft:initialize_light",
+            ChunkStatus::Light => "minecraft:light",
+            ChunkStatus::Spawn => "minecraft:spawn",
+            ChunkStatus::Full => "minecraft:full",
+        }
+    }
+
+    /// Whether the game has computed the light of chunks at this status.
+    pub fn has_light(&self) -> bool {
+        ChunkStatus::Light <= *self
+    }
 
-        let id = if let Some(id) = section.biomes.rev_palette.get(&biome) {
-            *id
+    /// Heightmaps the game keeps for chunks at this status.
+    fn heightmaps(&self) -> &'static [Heightmap] {
+        if *self < ChunkStatus::Noise {
+            &[]
+        } else if *self < ChunkStatus::Features {
+            &[Heightmap::OceanFloorWg, Heightmap::WorldSurfaceWg]
         } else {
-            let id = section.biomes.palette.len() as u16;
-            section.biomes.palette.push(biome.clone());
-            section.biomes.rev_palette.insert(biome, id);
-            id
-        };
-        section.biomes.data[(y << 8 | z << 4 | x) as usize] = id;
+            &[
+                Heightmap::MotionBlocking,
+                Heightmap::MotionBlockingNoLeaves,
+                Heightmap::OceanFloor,
+                Heightmap::WorldSurface,
+            ]
+        }
+    }
+}
 
-        Ok(())
+#[derive(Debug, Clone, Copy)]
+enum Heightmap {
+    OceanFloorWg,
+    WorldSurfaceWg,
+    MotionBlocking,
+    MotionBlockingNoLeaves,
+    OceanFloor,
+    WorldSurface,
+}
+
+impl Heightmap {
+    fn name(&self) -> &'static str {
+        match self {
+            Heightmap::OceanFloorWg => "OCEAN_FLOOR_WG",
+            Heightmap::WorldSurfaceWg => "WORLD_SURFACE_WG",
+            Heightmap::MotionBlocking => "MOTION_BLOCKING",
+            Heightmap::MotionBlockingNoLeaves => "MOTION_BLOCKING_NO_LEAVES",
+            Heightmap::OceanFloor => "OCEAN_FLOOR",
+            Heightmap::WorldSurface => "WORLD_SURFACE",
+        }
+    }
+
+    /// Whether `block` counts as the top of a column for this heightmap.
+    fn matches(&self, block: &BlockState) -> bool {
+        match self {
+            Heightmap::WorldSurfaceWg | Heightmap::WorldSurface => !block.is_air(),
+            Heightmap::OceanFloorWg | Heightmap::OceanFloor => block.blocks_motion(),
+            Heightmap::MotionBlocking => block.blocks_motion() || block.has_fluid(),
+            Heightmap::MotionBlockingNoLeaves => {
+                (block.blocks_motion() || block.has_fluid()) && !block.name.ends_with("_leaves")
+            }
+        }
+    }
+}
+
+/// Sets entry `i` of the nibble array `light`, creating it if missing.
+fn set_nibble(light: &mut Option<ByteArray>, i: usize, value: u8) {
+    let light = light.get_or_insert_with(|| ByteArray::new(vec![0; SECTION_BLOCKS / 2]));
+    let shift = (i & 1) * 4;
+    let byte = light[i >> 1] as u8 & !(0xf << shift) | (value.min(MAX_LIGHT) << shift);
+    light[i >> 1] = byte as i8;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledTick {
+    #[serde(rename = "i")]
+    pub id: String,
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    #[serde(rename = "t")]
+    pub delay: i32,
+    #[serde(rename = "p")]
+    pub priority: i32,
+}
+
+/// Block states and biomes interned once, so that chunks can be filled by integer ids.
+#[derive(Debug, Default)]
+pub struct ChunkPalette {
+    blocks: Vec<BlockState>,
+    block_ids: HashMap<BlockState, u16>,
+    biomes: Vec<String>,
+    biome_ids: HashMap<String, u16>,
+}
+
+impl ChunkPalette {
+    pub fn block_id(&mut self, block: BlockState) -> u16 {
+        if let Some(id) = self.block_ids.get(&block) {
+            return *id;
+        }
+        let id = self.blocks.len() as u16;
+        self.blocks.push(block.clone());
+        self.block_ids.insert(block, id);
+        id
+    }
+
+    pub fn block(&self, id: u16) -> &BlockState {
+        &self.blocks[id as usize]
+    }
+
+    pub fn biome(&self, id: u16) -> &str {
+        &self.biomes[id as usize]
+    }
+
+    pub fn biome_id(&mut self, biome: &str) -> u16 {
+        if let Some(id) = self.biome_ids.get(biome) {
+            return *id;
+        }
+        let id = self.biomes.len() as u16;
+        self.biomes.push(biome.to_string());
+        self.biome_ids.insert(biome.to_string(), id);
+        id
     }
 }
 
@@ -75,6 +545,44 @@ pub struct Section {
     pub y: i8,
     pub block_states: BlockStates,
     pub biomes: Biomes,
+    #[serde(
+        rename = "BlockLight",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub block_light: Option<ByteArray>,
+    #[serde(rename = "SkyLight", default, skip_serializing_if = "Option::is_none")]
+    pub sky_light: Option<ByteArray>,
+}
+
+impl Section {
+    pub fn new(y: i8) -> Section {
+        Section {
+            y,
+            block_states: BlockStates::new(),
+            biomes: Biomes::new(),
+            block_light: None,
+            sky_light: None,
+        }
+    }
+
+    /// Fills the blocks at `x`, `z` over the section-local height range `ys`.
+    pub fn fill_column(
+        &mut self,
+        x: u32,
+        z: u32,
+        ys: Range<u32>,
+        block: u16,
+        biome: u16,
+        palette: &ChunkPalette,
+    ) {
+        let block_id = self.block_states.local_id(block, palette);
+        let biome_id = self.biomes.local_id(biome, palette);
+        for y in ys.clone() {
+            self.block_states.data[(y << 8 | z << 4 | x) as usize] = block_id;
+        }
+        self.biomes.fill_column(x, z, ys, biome_id);
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -84,14 +592,59 @@ struct _BlockStates {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(from = "_BlockStates", into = "_BlockStates")]
+#[serde(try_from = "_BlockStates", into = "_BlockStates")]
 pub struct BlockStates {
     palette: Vec<BlockState>,
     #[serde(skip)]
     rev_palette: HashMap<BlockState, u16>,
+    // `ChunkPalette` id -> index of `palette`, `u16::MAX` if not added yet.
+    #[serde(skip)]
+    palette_lut: Vec<u16>,
     data: Vec<u16>,
 }
 
+impl BlockStates {
+    /// A section of air.
+    fn new() -> Self {
+        let air = BlockState {
+            name: AIR.to_string(),
+            properties: None,
+        };
+        BlockStates {
+            palette: vec![air.clone()],
+            rev_palette: HashMap::from([(air, 0)]),
+            palette_lut: Vec::new(),
+            data: vec![0u16; SECTION_BLOCKS],
+        }
+    }
+
+    fn palette_id(&mut self, block: &BlockState) -> u16 {
+        if let Some(id) = self.rev_palette.get(block) {
+            return *id;
+        }
+        let id = self.palette.len() as u16;
+        self.palette.push(block.clone());
+        self.rev_palette.insert(block.clone(), id);
+        id
+    }
+
+    fn local_id(&mut self, id: u16, palette: &ChunkPalette) -> u16 {
+        let id = id as usize;
+        if self.palette_lut.len() <= id {
+            self.palette_lut.resize(id + 1, u16::MAX);
+        }
+        if self.palette_lut[id] == u16::MAX {
+            self.palette_lut[id] = self.palette_id(&palette.blocks[id]);
+        }
+        self.palette_lut[id]
+    }
+
+    /// The block at section-local `x`, `y`, `z`.
+    pub fn get(&self, x: u32, y: u32, z: u32) -> &BlockState {
+        &self.palette[self.data[(y << 8 | z << 4 | x) as usize] as usize]
+    }
+}
+
 impl From<BlockStates> for _BlockStates {
     fn from(value: BlockStates) -> Self {
         let data = value.data;
@@ -122,14 +675,17 @@ impl From<BlockStates> for _BlockStates {
         let data = if palette.len() <= 1 {
             None
         } else {
-            Some(LongArray::new(pack_data(data, &palette.len(), 4)))
+            let bits = bits_per_entry(palette.len(), 4);
+            Some(LongArray::new(pack_data(&data, bits, Packing::Padded)))
         };
         _BlockStates { palette, data }
     }
 }
 
-impl From<_BlockStates> for BlockStates {
-    fn from(value: _BlockStates) -> Self {
+impl TryFrom<_BlockStates> for BlockStates {
+    type Error = anyhow::Error;
+
+    fn try_from(value: _BlockStates) -> Result<Self> {
         let palette = if value.palette.is_empty() {
             vec![BlockState {
                 name: AIR.to_string(),
@@ -144,48 +700,24 @@ impl From<_BlockStates> for BlockStates {
         }
         let data = value.data;
         let data = if let Some(data) = data {
-            unpack_data(data.into_inner(), &palette.len(), 4)
+            let bits = bits_per_entry(palette.len(), 4);
+            let data = unpack_data(&data, bits, SECTION_BLOCKS, Packing::Padded)?;
+            ensure!(
+                data.iter().all(|id| (*id as usize) < palette.len()),
+                "Block state data refers past the end of its palette of {} entries",
+                palette.len()
+            );
+            data
         } else {
             vec![0u16; 16 * 16 * 16]
         };
-        BlockStates {
+        Ok(BlockStates {
             palette,
             rev_palette,
+            palette_lut: Vec::new(),
             data,
-        }
-    }
-}
-
-fn pack_data(data: Vec<u16>, palette_len: &usize, min_bits: u32) -> Vec<i64> {
-    let bits = (usize::BITS - (palette_len - 1).leading_zeros()).max(min_bits);
-    let data_par_i64 = i64::BITS / bits;
-    let mut packed_data = Vec::new();
-    for (i, data_item) in data.iter().enumerate() {
-        let data_item = *data_item as i64;
-        let current_ptr = i % data_par_i64 as usize * bits as usize;
-        let encoded_data_item = if current_ptr == 0 {
-            packed_data.push(0);
-            0i64
-        } else {
-            *packed_data.last().unwrap()
-        };
-        *packed_data.last_mut().unwrap() = encoded_data_item | data_item << current_ptr;
-    }
-    packed_data
-}
-
-fn unpack_data(data: Vec<i64>, palette_len: &usize, min_bits: u32) -> Vec<u16> {
-    let bits = (usize::BITS - (palette_len - 1).leading_zeros()).max(min_bits);
-    let blocks_par_i64 = i64::BITS / bits;
-    let mut unpacked_data = Vec::new();
-    for mut data_item in data {
-        for _ in 0..blocks_par_i64 {
-            let id = (data_item & ((1 << bits) - 1)) as u16;
-            data_item >>= bits;
-            unpacked_data.push(id);
-        }
+        })
     }
-    unpacked_data
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
@@ -196,6 +728,53 @@ pub struct BlockState {
     pub properties: Option<BTreeMap<String, String>>,
 }
 
+impl BlockState {
+    pub fn is_air(&self) -> bool {
+        matches!(
+            self.name.as_str(),
+            AIR | "minecraft:cave_air" | "minecraft:void_air"
+        )
+    }
+
+    /// Whether the block holds water or lava.
+    pub fn has_fluid(&self) -> bool {
+        matches!(self.name.as_str(), WATER | "minecraft:lava")
+            || WATER_PLANTS.contains(&self.name.as_str())
+            || self
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.get("waterlogged"))
+                .is_some_and(|waterlogged| waterlogged == "true")
+    }
+
+    /// Whether entities collide with the block, an approximation from the block name.
+    pub fn blocks_motion(&self) -> bool {
+        let name = self.name.as_str();
+        !(self.is_air()
+            || name == WATER
+            || WATER_PLANTS.contains(&name)
+            || NON_SOLID_BLOCKS.contains(&name)
+            || NON_SOLID_SUFFIXES
+                .iter()
+                .any(|suffix| name.ends_with(suffix)))
+    }
+}
+
+impl Display for BlockState {
+    /// Formats the state like commands do, `name[key=value,...]`.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(properties) = self.properties.as_ref().filter(|p| !p.is_empty()) {
+            let properties = properties
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>();
+            write!(f, "[{}]", properties.join(","))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct _Biomes {
     palette: Vec<String>,
@@ -203,99 +782,332 @@ pub struct _Biomes {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(from = "_Biomes", into = "_Biomes")]
+#[serde(try_from = "_Biomes", into = "_Biomes")]
 pub struct Biomes {
     palette: Vec<String>,
     #[serde(skip)]
     rev_palette: HashMap<String, u16>,
-    data: Vec<u16>,
+    // `ChunkPalette` id -> index of `palette`, `u16::MAX` if not added yet.
+    #[serde(skip)]
+    palette_lut: Vec<u16>,
+    #[serde(skip)]
+    sampling: BiomeSampling,
+    // Indexed by `y << 4 | z << 2 | x` in cell coordinates.
+    cells: Vec<BiomeCell>,
+}
+
+/// The biomes written into one 4x4x4 cell.
+#[derive(Debug, Clone)]
+struct BiomeCell {
+    // `UNSET_BIOME` if the center block has not been written.
+    center: u16,
+    // Number of written blocks per biome, in the order the biomes were first written.
+    counts: Vec<(u16, u16)>,
+}
+
+impl BiomeCell {
+    fn new() -> Self {
+        BiomeCell {
+            center: UNSET_BIOME,
+            counts: Vec::new(),
+        }
+    }
+
+    /// A cell read from a stored chunk. Its biome counts as no block, so the first block
+    /// written into the cell outvotes it.
+    fn filled(biome: u16) -> Self {
+        BiomeCell {
+            center: biome,
+            counts: vec![(biome, 0)],
+        }
+    }
+
+    fn add(&mut self, biome: u16, count: u16) {
+        match self.counts.iter_mut().find(|(id, _)| *id == biome) {
+            Some((_, c)) => *c += count,
+            None => self.counts.push((biome, count)),
+        }
+    }
+
+    /// Picks the biome of the cell, or `None` if no block of the cell has been written.
+    fn sample(&self, sampling: BiomeSampling) -> Option<u16> {
+        if sampling == BiomeSampling::Center && self.center != UNSET_BIOME {
+            return Some(self.center);
+        }
+        // Majority vote, ties go to the biome written first.
+        let mut majority: Option<(u16, u16)> = None;
+        for (id, count) in &self.counts {
+            if majority.is_none_or(|(_, max)| max < *count) {
+                majority = Some((*id, *count));
+            }
+        }
+        majority.map(|(id, _)| id)
+    }
+}
+
+impl Biomes {
+    /// A section without any biome written yet.
+    fn new() -> Self {
+        Biomes {
+            palette: Vec::new(),
+            rev_palette: HashMap::new(),
+            palette_lut: Vec::new(),
+            sampling: BiomeSampling::default(),
+            cells: vec![BiomeCell::new(); BIOME_CELLS],
+        }
+    }
+
+    fn palette_id(&mut self, biome: &str) -> u16 {
+        if let Some(id) = self.rev_palette.get(biome) {
+            return *id;
+        }
+        let id = self.palette.len() as u16;
+        self.palette.push(biome.to_string());
+        self.rev_palette.insert(biome.to_string(), id);
+        id
+    }
+
+    fn local_id(&mut self, id: u16, palette: &ChunkPalette) -> u16 {
+        let id = id as usize;
+        if self.palette_lut.len() <= id {
+            self.palette_lut.resize(id + 1, u16::MAX);
+        }
+        if self.palette_lut[id] == u16::MAX {
+            self.palette_lut[id] = self.palette_id(&palette.biomes[id]);
+        }
+        self.palette_lut[id]
+    }
+
+    /// The biome of the cell holding section-local `x`, `y`, `z`, `None` if it has none.
+    pub fn get(&self, x: u32, y: u32, z: u32) -> Option<&str> {
+        let cell = &self.cells[((y >> 2) << 4 | (z >> 2) << 2 | x >> 2) as usize];
+        cell.sample(self.sampling)
+            .map(|id| self.palette[id as usize].as_str())
+    }
+
+    /// Records `biome` for the blocks at `x`, `z` over the section-local height range `ys`.
+    fn fill_column(&mut self, x: u32, z: u32, ys: Range<u32>, biome: u16) {
+        let xz = ((z >> 2) << 2 | x >> 2) as usize;
+        let is_center_column = x & 3 == 2 && z & 3 == 2;
+        let mut y = ys.start;
+        while y < ys.end {
+            let cell_end = (y & !3) + 4;
+            let cell = &mut self.cells[((y >> 2) << 4) as usize | xz];
+            cell.add(biome, (ys.end.min(cell_end) - y) as u16);
+            if is_center_column && (y..ys.end.min(cell_end)).contains(&((y & !3) | 2)) {
+                cell.center = biome;
+            }
+            y = cell_end;
+        }
+    }
 }
 
 impl From<Biomes> for _Biomes {
     fn from(value: Biomes) -> Self {
-        let data = value.data;
-        let mut new_data = vec![0; data.len()];
-        let mut used_pallette_items = HashMap::<u16, usize>::new();
+        let mut cells = value
+            .cells
+            .iter()
+            .map(|cell| cell.sample(value.sampling))
+            .collect::<Vec<_>>();
+        // Cells without any written block take the nearest written cell above, or else below,
+        // so that the space under a column's lowest data point keeps the column's biome.
+        let sampled_cells = cells.clone();
+        for (i, cell) in cells.iter_mut().enumerate() {
+            if cell.is_some() {
+                continue;
+            }
+            let (xz, cell_y) = (i & 0xf, i >> 4);
+            let above = (cell_y + 1..4).map(|y| sampled_cells[y << 4 | xz]);
+            let below = (0..cell_y).rev().map(|y| sampled_cells[y << 4 | xz]);
+            *cell = above.chain(below).flatten().next();
+        }
+
+        let mut used_pallette_items = HashMap::<Option<u16>, usize>::new();
         let mut palette = Vec::new();
-        for (i, data_item) in data.iter().enumerate() {
-            let idx = if let Some(idx) = used_pallette_items.get(data_item) {
+        let mut new_data = Vec::with_capacity(BIOME_CELLS);
+        for cell in cells {
+            let idx = if let Some(idx) = used_pallette_items.get(&cell) {
                 *idx
             } else {
-                let state = value.palette[*data_item as usize].clone();
-                palette.push(state);
+                let biome = match cell {
+                    Some(id) => value.palette[id as usize].clone(),
+                    None => String::from(DEFAULT_BIOME),
+                };
+                palette.push(biome);
                 let idx = used_pallette_items.len();
-                used_pallette_items.insert(*data_item, idx);
+                used_pallette_items.insert(cell, idx);
                 idx
             };
-            new_data[i] = idx as u16;
+            new_data.push(idx as u16);
         }
-        let data = new_data;
-        let palette = if palette.is_empty() {
-            vec![String::from("minecraft:plains")]
-        } else {
-            palette
-        };
         let data = if palette.len() <= 1 {
             None
         } else {
-            let mut new_data = Vec::new();
-            for i in 0..(4 * 4 * 4) {
-                let mut biomes = Vec::new();
-                let x = (i & 3) << 2;
-                let z = ((i >> 2) & 3) << 2;
-                let y = ((i >> 4) & 3) << 2;
-                for p in 0..64 {
-                    let y = y | (p >> 4) & 3;
-                    let z = z | (p >> 2) & 3;
-                    let x = x | p & 3;
-                    biomes.push(data[y << 4 | z << 2 | x]);
-                }
-                if i % 2 == 0 {
-                    new_data.push(*biomes.first().unwrap());
-                } else {
-                    new_data.push(*biomes.last().unwrap());
-                }
-            }
-            Some(LongArray::new(pack_data(new_data, &palette.len(), 0)))
+            let bits = bits_per_entry(palette.len(), 0);
+            Some(LongArray::new(pack_data(&new_data, bits, Packing::Padded)))
         };
         _Biomes { palette, data }
     }
 }
 
-impl From<_Biomes> for Biomes {
-    fn from(value: _Biomes) -> Self {
-        let palette = if value.palette.is_empty() {
-            vec![String::from("minecraft:plains")]
-        } else {
-            value.palette
-        };
+impl TryFrom<_Biomes> for Biomes {
+    type Error = anyhow::Error;
+
+    fn try_from(value: _Biomes) -> Result<Self> {
+        let palette = value.palette;
         let mut rev_palette = HashMap::new();
         for (i, state) in palette.iter().enumerate() {
             rev_palette.insert(state.clone(), i as u16);
         }
-        let data = value.data;
-        let data = if let Some(data) = data {
-            let data = unpack_data(data.into_inner(), &palette.len(), 0);
-            let mut new_data = vec![0; data.len() * (4 * 4 * 4)];
-            for (i, data_item) in data.into_iter().enumerate() {
-                let x = (i & 3) << 2;
-                let z = ((i >> 2) & 3) << 2;
-                let y = ((i >> 4) & 3) << 2;
-                for p in 0..64 {
-                    let y = y | (p >> 4) & 3;
-                    let z = z | (p >> 2) & 3;
-                    let x = x | p & 3;
-                    new_data[y << 4 | z << 2 | x] = data_item;
-                }
-            }
-            new_data
+        let cells = if palette.is_empty() {
+            vec![BiomeCell::new(); BIOME_CELLS]
+        } else if let Some(data) = value.data {
+            let bits = bits_per_entry(palette.len(), 0);
+            let data = unpack_data(&data, bits, BIOME_CELLS, Packing::Padded)?;
+            ensure!(
+                data.iter().all(|id| (*id as usize) < palette.len()),
+                "Biome data refers past the end of its palette of {} entries",
+                palette.len()
+            );
+            data.into_iter().map(BiomeCell::filled).collect()
         } else {
-            vec![0u16; 16 * 16 * 16]
+            vec![BiomeCell::filled(0); BIOME_CELLS]
         };
-        Biomes {
+        Ok(Biomes {
             palette,
             rev_palette,
-            data,
+            palette_lut: Vec::new(),
+            sampling: BiomeSampling::default(),
+            cells,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(name: &str) -> BlockState {
+        BlockState {
+            name: name.to_string(),
+            properties: None,
         }
     }
+
+    fn heightmap(chunk: &Chunk, name: &str) -> Vec<u16> {
+        let Some(Value::Compound(heightmaps)) = chunk.other.get("Heightmaps") else {
+            panic!("no heightmaps");
+        };
+        let Some(Value::LongArray(heights)) = heightmaps.get(name) else {
+            panic!("no {name} heightmap");
+        };
+        unpack_data(heights, 9, 256, Packing::Padded).unwrap()
+    }
+
+    #[test]
+    fn full_chunks_get_light_and_final_heightmaps() {
+        let format = ChunkFormat::default();
+        let mut chunk = Chunk::new(0, 0, &format);
+        chunk.set_status(ChunkStatus::Full);
+        assert!(chunk.is_light_on);
+        let mut palette = ChunkPalette::default();
+        let biome = palette.biome_id(DEFAULT_BIOME);
+        let stone = palette.block_id(block("minecraft:stone"));
+        let water = palette.block_id(block(WATER));
+        let leaves = palette.block_id(block("minecraft:oak_leaves"));
+        let grass = palette.block_id(block("minecraft:short_grass"));
+        chunk
+            .fill_column(0, 0, -64..60, stone, biome, &palette)
+            .unwrap();
+        chunk
+            .fill_column(0, 0, 60..63, water, biome, &palette)
+            .unwrap();
+        chunk
+            .fill_column(1, 0, -64..70, stone, biome, &palette)
+            .unwrap();
+        chunk
+            .fill_column(1, 0, 70..71, grass, biome, &palette)
+            .unwrap();
+        chunk
+            .fill_column(1, 0, 75..77, leaves, biome, &palette)
+            .unwrap();
+        chunk.update_heightmaps(ChunkStatus::Full);
+
+        // Heights are the block height above the top block, counted from the world bottom.
+        assert_eq!(&heightmap(&chunk, "WORLD_SURFACE")[..3], &[127, 141, 0]);
+        assert_eq!(&heightmap(&chunk, "MOTION_BLOCKING")[..3], &[127, 141, 0]);
+        assert_eq!(
+            &heightmap(&chunk, "MOTION_BLOCKING_NO_LEAVES")[..3],
+            &[127, 134, 0]
+        );
+        assert_eq!(&heightmap(&chunk, "OCEAN_FLOOR")[..3], &[124, 141, 0]);
+
+        chunk.fill_light(1, 0, 70..72, 3, 14);
+        let section = &chunk.sections[(70 - format.min_y) as usize >> 4];
+        let i = ((70 & 0xf) << 8 | 1) as usize;
+        let nibble = |light: &Option<ByteArray>| (light.as_ref().unwrap()[i >> 1] as u8 >> 4) & 0xf;
+        assert_eq!(nibble(&section.block_light), 3);
+        assert_eq!(nibble(&section.sky_light), 14);
+
+        chunk.set_status(ChunkStatus::Noise);
+        chunk.update_heightmaps(ChunkStatus::Noise);
+        assert!(!chunk.is_light_on);
+        assert!(
+            chunk
+                .sections
+                .iter()
+                .all(|section| section.sky_light.is_none())
+        );
+        assert_eq!(&heightmap(&chunk, "WORLD_SURFACE_WG")[..2], &[127, 141]);
+    }
+
+    #[test]
+    fn templates_drop_positioned_tags() {
+        let format = ChunkFormat::default();
+        let mut template = Chunk::new(5, 7, &format);
+        template.inhabited_time = 1200;
+        template.block_entities.push(Value::Compound(HashMap::from([
+            (
+                "id".to_string(),
+                Value::String("minecraft:chest".to_string()),
+            ),
+            ("x".to_string(), Value::Int(80)),
+            ("y".to_string(), Value::Int(64)),
+            ("z".to_string(), Value::Int(112)),
+        ])));
+        template.schedule_fluid_tick(0, 64, 0, WATER);
+        template.structures.references.insert(
+            "minecraft:village_plains".to_string(),
+            Value::LongArray(LongArray::new(vec![0])),
+        );
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), fastnbt::to_bytes(&template).unwrap()).unwrap();
+
+        let mut chunk = Chunk::load_template(file.path(), &format).unwrap();
+        chunk.set_chunk_pos(-3, 2);
+        let chunk = fastnbt::from_bytes::<Chunk>(&fastnbt::to_bytes(&chunk).unwrap()).unwrap();
+        assert_eq!((chunk.x, chunk.z), (-3, 2));
+        assert_eq!(chunk.inhabited_time, 1200);
+        assert!(chunk.block_entities.is_empty());
+        assert!(chunk.fluid_ticks.is_empty());
+        assert!(chunk.structures.references.is_empty());
+    }
+
+    #[test]
+    fn written_biomes_outvote_stored_biomes() {
+        let mut biomes = Biomes::try_from(_Biomes {
+            palette: vec![DEFAULT_BIOME.to_string()],
+            data: None,
+        })
+        .unwrap();
+        let desert = biomes.palette_id("minecraft:desert");
+        biomes.fill_column(0, 0, 0..1, desert);
+        assert_eq!(biomes.get(0, 0, 0), Some("minecraft:desert"));
+        assert_eq!(biomes.get(4, 0, 0), Some(DEFAULT_BIOME));
+
+        biomes.sampling = BiomeSampling::Center;
+        assert_eq!(biomes.get(0, 0, 0), Some(DEFAULT_BIOME));
+        biomes.fill_column(2, 2, 2..3, desert);
+        assert_eq!(biomes.get(0, 0, 0), Some("minecraft:desert"));
+    }
 }
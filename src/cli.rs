@@ -9,39 +9,607 @@ use std::{
 
 use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::{data::RegionPos, worldgen::WorldGenStatus};
+use crate::{
+    chunk::{BiomeSampling, ChunkFormat, ChunkStatus},
+    columns::TableFormat,
+    data::RegionPos,
+    decompress::CompressionMode,
+    map::{ColumnPos, MapKind},
+    merge::OverlapPolicy,
+    mesh::MeshFormat,
+    region::{ChunkCompression, RegionFormat},
+    sql::DBOpenMode,
+    volume::BlockPos,
+    worldgen::{Filler, WorldGenStatus},
+};
 
 #[derive(Debug, Parser)]
-#[command(version, about)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    flatten_help = true
+)]
 /// Command-line arguments for the application.
 ///
+/// Without a subcommand the DH data is converted into region files.
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub convert: ConvertArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Renders PNG overview maps of the DH data.
+    Map(MapArgs),
+    /// Exports a box of blocks as a Sponge schematic (`.schem`).
+    Schem(SchemArgs),
+    /// Exports a box of blocks as a Litematica schematic (`.litematic`).
+    Litematic(LitematicArgs),
+    /// Exports the terrain as a mesh, for a box or one file per region.
+    Mesh(MeshArgs),
+    /// Exports the decoded DH data points as JSON Lines or CSV.
+    Columns(ColumnsArgs),
+    /// Builds a DH database from the Anvil region files of a world.
+    Anvil2dh(Anvil2dhArgs),
+    /// Generates the coarser detail levels of a DH database from its detail level 0.
+    Lod(LodArgs),
+    /// Merges several DH databases into a new one.
+    Merge(MergeArgs),
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments selecting the DH databases to read.
+///
+/// # Fields
+/// - `db_mode`: How to open the input databases (`read-only`, `immutable` or `snapshot`).
+/// - `overlap`: Which section to keep when several databases store one at the same position.
+/// - `db_paths`: Paths to the input `.sqlite` files containing dh lod data, read as one.
+pub struct DBArgs {
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DBOpenMode::ReadOnly,
+        help = "How to open the input databases. Use `snapshot` or `immutable` when the game or server is still running."
+    )]
+    pub db_mode: DBOpenMode,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OverlapPolicy::Newest,
+        help = "Which section to keep when several input databases store one at the same position."
+    )]
+    pub overlap: OverlapPolicy,
+    #[arg(
+        required = true,
+        num_args = 1..,
+        help = "Paths to the input `.sqlite` files containing dh lod data. Several files are read as one world."
+    )]
+    pub db_paths: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments selecting the regions to read, shared by the modes that walk whole regions.
+///
+/// # Fields
+/// - `threads`: Number of threads to use.
+///   Set to `0` to automatically select the optimal number based on available CPU cores.
+/// - `range`: Limits the region coordinates that are read.
+pub struct InputArgs {
+    #[arg(
+        short,
+        long,
+        default_value_t = 0,
+        help = "Number of threads to use. Set to 0 for automatic selection based on available CPU cores."
+    )]
+    pub threads: u8,
+    #[arg(
+        short,
+        long,
+        default_value_t = 0,
+        help = "Limits the generation range of region coordinates. If set to 0, all regions are generated. If set to 1 or higher, only regions where x and z are in the range -range to range-1 are generated."
+    )]
+    pub range: u32,
+    #[command(flatten)]
+    pub db: DBArgs,
+}
+
+#[derive(Debug, clap::Args)]
+/// Version and height of the written chunks or blocks.
+///
+/// # Fields
+/// - `data_version`: Minecraft data version written into the output.
+/// - `min_y`, `height`: Lowest block height and height of the world.
+pub struct FormatArgs {
+    #[arg(
+        long,
+        default_value_t = ChunkFormat::default().data_version,
+        help = "DataVersion written into the output, matching the Minecraft version that loads it. 2844 (1.18) or later."
+    )]
+    pub data_version: i32,
+    #[arg(
+        long,
+        default_value_t = ChunkFormat::default().min_y,
+        allow_negative_numbers = true,
+        help = "Lowest block height of the world, a multiple of 16."
+    )]
+    pub min_y: i32,
+    #[arg(
+        long,
+        default_value_t = ChunkFormat::default().section_count as u32 * 16,
+        help = "Height of the world in blocks, a multiple of 16."
+    )]
+    pub height: u32,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the region file conversion.
+///
 /// # Fields
 /// - `out`: Specifies the output directory for generated `.mca` files.
 ///   Defaults to `./region`.
-/// - `threads`: Number of threads to use for world generation.
-///   Set to `0` to automatically select the optimal number based on available CPU cores.
-/// - `db_path`: Path to the input `.sqlite` file containing dh lod data.
-pub struct Args {
+/// - `biome_sampling`: How each 4x4x4 biome cell is downsampled (`majority` or `center`).
+/// - `fluid_ticks`: Schedules fluid ticks for reconstructed water.
+/// - `filler`: Block to fill the space under each column with, or `auto`.
+/// - `chunk_status`: Generation status written into every chunk.
+/// - `format`: Minecraft data version and world height of the chunks.
+/// - `chunk_template`: NBT chunk file that every generated chunk starts from.
+/// - `region_format`: Format of the written region files.
+/// - `chunk_compression`, `compression_level`: How chunks are compressed in region files.
+///   The level only applies to zlib, gzip and Linear regions.
+pub struct ConvertArgs {
     #[arg(short, long, default_value_t = String::from("./region"), help="Specifies the output directory for generated `.mca` files.")]
     pub out: String,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = BiomeSampling::Majority,
+        help = "How the biome of each 4x4x4 biome cell is picked from the biomes of its blocks."
+    )]
+    pub biome_sampling: BiomeSampling,
+    #[arg(
+        long,
+        help = "Schedules fluid ticks for reconstructed water, so the game settles its flow on load."
+    )]
+    pub fluid_ticks: bool,
+    #[arg(
+        long,
+        value_name = "BLOCK",
+        help = "Fills the space under the lowest stored block of each column down to a bedrock floor. `auto` picks stone, deepslate below y=0 and netherrack in the Nether, any other value must be a namespaced block id like `minecraft:stone`."
+    )]
+    pub filler: Option<Filler>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ChunkStatus::Full,
+        help = "Generation status written into every chunk. The game runs the steps after it when loading, so statuses before `features` let it decorate over the terrain. From `light` on, the light stored in the DH data is written."
+    )]
+    pub chunk_status: ChunkStatus,
+    #[command(flatten)]
+    pub format: FormatArgs,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "NBT chunk file whose tags are copied into every generated chunk, except its block entities, ticks and structures. Its DataVersion and sections must match `--data-version`, `--min-y` and `--height`."
+    )]
+    pub chunk_template: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = RegionFormat::Anvil,
+        help = "Format of the written region files, `anvil` (.mca) or `linear` (.linear)."
+    )]
+    pub region_format: RegionFormat,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ChunkCompression::Zlib,
+        help = "Compression of the chunks in Anvil region files. `none` needs Minecraft 1.15.1 or later and `lz4` needs 1.20.5 or later."
+    )]
+    pub chunk_compression: ChunkCompression,
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(0..=22),
+        help = "zlib or gzip compression level from 0 (none) to 9 (smallest), or zstd level up to 22 for Linear regions. Defaults to 1. Not accepted with `--chunk-compression none` or `lz4`."
+    )]
+    pub compression_level: Option<u32>,
+    #[command(flatten)]
+    pub input: InputArgs,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the map rendering.
+///
+/// # Fields
+/// - `out`: Output directory for the PNG files. Defaults to `./map`.
+/// - `kinds`: Which maps to render.
+/// - `stitch`: Renders one image over all regions instead of one per region.
+/// - `from`, `to`: Opposite corners of the rendered area. Only regions overlapping it are read.
+/// - `block_colors`: File with the colors of blocks for the block map.
+/// - `biome_colors`: File with the colors of biomes for the biome map.
+/// - `height`: World height that the heightmap's gray levels span.
+pub struct MapArgs {
+    #[arg(
+        short,
+        long,
+        default_value_t = String::from("./map"),
+        help = "Output directory for the PNG files."
+    )]
+    pub out: String,
+    #[arg(
+        short,
+        long = "kind",
+        value_enum,
+        default_values_t = [MapKind::Height, MapKind::Blocks, MapKind::Biomes],
+        help = "Map to render, can be given several times."
+    )]
+    pub kinds: Vec<MapKind>,
+    #[arg(
+        long,
+        help = "Renders one image covering all regions, or the area of `--from` and `--to`, instead of one image per region."
+    )]
+    pub stitch: bool,
+    #[arg(
+        long,
+        value_name = "X,Z",
+        allow_hyphen_values = true,
+        requires = "to",
+        help = "One corner of the area to render in block coordinates. Only regions overlapping the area are read, and a stitched image covers exactly the area."
+    )]
+    pub from: Option<ColumnPos>,
+    #[arg(
+        long,
+        value_name = "X,Z",
+        allow_hyphen_values = true,
+        requires = "from",
+        help = "The opposite corner of the rendered area, included in the area."
+    )]
+    pub to: Option<ColumnPos>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Block colors for the block map, one `<block id> <#rrggbb>` per line. Lines starting with `#` are comments. Listed blocks override the built-in colors."
+    )]
+    pub block_colors: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Biome colors for the biome map, in the same format as `--block-colors`."
+    )]
+    pub biome_colors: Option<String>,
+    #[arg(
+        long,
+        default_value_t = 384,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "World height in blocks, mapped from black to white in the heightmap."
+    )]
+    pub height: u32,
+    #[command(flatten)]
+    pub input: InputArgs,
+}
+
+pub fn regions_progressbar(regions_count: u64) -> ProgressBar {
+    let progressbar = ProgressBar::new(regions_count);
+    progressbar.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "[{elapsed_precise}] {spinner} [{eta}] [{bar:40.green/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap()
+            .progress_chars("=>.."),
+    );
+    progressbar
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments selecting a box of blocks and how it is reconstructed, shared by the box exports.
+///
+/// # Fields
+/// - `from`, `to`: Opposite corners of the exported box, both included.
+/// - `filler`: Block to fill the space under each column with, or `auto`.
+/// - `format`: Minecraft data version and world height the DH data was saved with.
+pub struct BoxArgs {
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        allow_hyphen_values = true,
+        help = "One corner of the exported box in block coordinates."
+    )]
+    pub from: BlockPos,
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        allow_hyphen_values = true,
+        help = "The opposite corner of the exported box, included in the box. The box may hold at most 268435456 blocks."
+    )]
+    pub to: BlockPos,
+    #[arg(
+        long,
+        value_name = "BLOCK",
+        help = "Fills the space under the lowest stored block of each column, like the conversion does."
+    )]
+    pub filler: Option<Filler>,
+    #[command(flatten)]
+    pub format: FormatArgs,
+    #[command(flatten)]
+    pub db: DBArgs,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the schematic export.
+///
+/// # Fields
+/// - `out`: Path of the written schematic file.
+/// - `area`: The exported box.
+pub struct SchemArgs {
+    #[arg(
+        short,
+        long,
+        default_value_t = String::from("./export.schem"),
+        help = "Path of the written schematic file."
+    )]
+    pub out: String,
+    #[command(flatten)]
+    pub area: BoxArgs,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the Litematica export.
+///
+/// # Fields
+/// - `out`: Path of the written schematic file.
+/// - `name`, `author`, `description`: Metadata shown by Litematica.
+/// - `area`: The exported box.
+pub struct LitematicArgs {
+    #[arg(
+        short,
+        long,
+        default_value_t = String::from("./export.litematic"),
+        help = "Path of the written schematic file."
+    )]
+    pub out: String,
+    #[arg(
+        long,
+        help = "Name of the schematic. Defaults to the output file name, or `Unnamed`."
+    )]
+    pub name: Option<String>,
+    #[arg(long, default_value_t = String::from("dh2anvil"), help = "Author of the schematic.")]
+    pub author: String,
+    #[arg(long, default_value_t = String::new(), help = "Description of the schematic.")]
+    pub description: String,
+    #[command(flatten)]
+    pub area: BoxArgs,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the mesh export.
+///
+/// # Fields
+/// - `out`: Output directory for the mesh files.
+/// - `mesh_format`: File format of the meshes (`obj` or `glb`).
+/// - `from`, `to`: Opposite corners of an exported box. Without them every region is exported.
+/// - `block_colors`: File with the material colors of blocks.
+/// - `filler`: Block to fill the space under each column with, or `auto`.
+/// - `format`: Minecraft data version and world height the DH data was saved with.
+pub struct MeshArgs {
+    #[arg(
+        short,
+        long,
+        default_value_t = String::from("./mesh"),
+        help = "Output directory for the mesh files. A box is written as `terrain.<ext>`, regions as `r.<x>.<z>.<ext>`."
+    )]
+    pub out: String,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = MeshFormat::Obj,
+        help = "File format of the meshes, `obj` (with a `.mtl` file) or `glb`."
+    )]
+    pub mesh_format: MeshFormat,
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        allow_hyphen_values = true,
+        requires = "to",
+        help = "One corner of a box to export as one mesh. Without `--from` and `--to` every region is exported to its own mesh."
+    )]
+    pub from: Option<BlockPos>,
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        allow_hyphen_values = true,
+        requires = "from",
+        help = "The opposite corner of the exported box, included in the box."
+    )]
+    pub to: Option<BlockPos>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Material colors, in the same format as the `--block-colors` of `map`."
+    )]
+    pub block_colors: Option<String>,
+    #[arg(
+        long,
+        value_name = "BLOCK",
+        help = "Fills the space under the lowest stored block of each column, like the conversion does."
+    )]
+    pub filler: Option<Filler>,
+    #[command(flatten)]
+    pub format: FormatArgs,
+    #[command(flatten)]
+    pub input: InputArgs,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the column data export.
+///
+/// # Fields
+/// - `out`: Output file. Defaults to `./columns.jsonl` or `./columns.csv`.
+/// - `table_format`: File format of the rows (`jsonl` or `csv`).
+pub struct ColumnsArgs {
+    #[arg(
+        short,
+        long,
+        help = "Output file. Defaults to `./columns.jsonl` or `./columns.csv` depending on `--table-format`."
+    )]
+    pub out: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = TableFormat::Jsonl,
+        help = "File format of the rows, `jsonl` (one JSON object per line) or `csv`."
+    )]
+    pub table_format: TableFormat,
+    #[command(flatten)]
+    pub input: InputArgs,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the conversion from Anvil region files into a DH database.
+///
+/// # Fields
+/// - `region_dir`: Directory with the `.mca` files to read.
+/// - `out`: DH database to write. Existing sections at the same positions are replaced.
+/// - `compression_mode`: Compression of the written section data (`uncompressed` or `lzma2`).
+/// - `min_y`, `height`: Lowest block height and height of the world.
+/// - `threads`, `range`: Same as for the conversion.
+pub struct Anvil2dhArgs {
+    #[arg(help = "Directory with the `.mca` region files of the world or dimension.")]
+    pub region_dir: String,
+    #[arg(
+        short,
+        long,
+        default_value_t = String::from("./DistantHorizons.sqlite"),
+        help = "DH database to write. It is created if missing, existing sections at the same positions are replaced."
+    )]
+    pub out: String,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = CompressionMode::Lzma2,
+        help = "Compression of the written section data."
+    )]
+    pub compression_mode: CompressionMode,
+    #[arg(
+        long,
+        default_value_t = ChunkFormat::default().min_y,
+        allow_negative_numbers = true,
+        help = "Lowest block height of the world."
+    )]
+    pub min_y: i32,
+    #[arg(
+        long,
+        default_value_t = ChunkFormat::default().section_count as u32 * 16,
+        help = "Height of the world in blocks, at most 4096."
+    )]
+    pub height: u32,
     #[arg(
         short,
         long,
         default_value_t = 0,
-        help = "Number of threads to use for world generation. Set to 0 for automatic selection based on available CPU cores."
+        help = "Number of threads to use. Set to 0 for automatic selection based on available CPU cores."
     )]
     pub threads: u8,
     #[arg(
         short,
         long,
         default_value_t = 0,
-        help = "Limits the generation range of region coordinates. If set to 0, all regions are generated. If set to 1 or higher, only regions where x and z are in the range -range to range-1 are generated."
+        help = "Limits the range of region coordinates read. If set to 0, all regions are read. If set to 1 or higher, only regions where x and z are in the range -range to range-1 are read."
     )]
     pub range: u32,
-    #[arg(help = "Path to the input `.sqlite` file containing dh lod data.")]
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Number of coarser detail levels to generate after the blocks are written, like the `lod` command does."
+    )]
+    pub lod_levels: u8,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the detail level generation.
+///
+/// # Fields
+/// - `db_path`: DH database to read detail level 0 from.
+/// - `out`: Copy of `db_path` to generate the detail levels in.
+/// - `in_place`: Whether to generate the detail levels in `db_path` itself.
+/// - `levels`: Number of detail levels above 0 to generate.
+/// - `compression_mode`: Compression of the written section data.
+/// - `threads`: Same as for the conversion.
+pub struct LodArgs {
+    #[arg(help = "Path to the `.sqlite` file containing dh lod data.")]
     pub db_path: String,
+    #[arg(
+        short,
+        long,
+        conflicts_with = "in_place",
+        required_unless_present = "in_place",
+        help = "Path of the new `.sqlite` file to copy the database into and generate the detail levels in. Must not exist."
+    )]
+    pub out: Option<String>,
+    #[arg(
+        long,
+        help = "Generate the detail levels in the input database itself. Close the game or server first and keep a backup, existing sections above level 0 are replaced."
+    )]
+    pub in_place: bool,
+    #[arg(
+        short,
+        long,
+        default_value_t = 6,
+        help = "Number of detail levels above 0 to generate. Each level halves the resolution of the one below it, existing sections of these levels are replaced."
+    )]
+    pub levels: u8,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = CompressionMode::Lzma2,
+        help = "Compression of the written section data."
+    )]
+    pub compression_mode: CompressionMode,
+    #[arg(
+        short,
+        long,
+        default_value_t = 0,
+        help = "Number of threads to use. Set to 0 for automatic selection based on available CPU cores."
+    )]
+    pub threads: u8,
+}
+
+#[derive(Debug, clap::Args)]
+/// Arguments of the database merge.
+///
+/// # Fields
+/// - `out`: Merged database to create.
+/// - `lod_levels`: Number of detail levels above 0 to rebuild from the merged sections.
+/// - `compression_mode`: Compression of the rebuilt section data.
+/// - `db`: Databases to merge and which of their sections to keep where they overlap.
+pub struct MergeArgs {
+    #[arg(
+        short,
+        long,
+        default_value_t = String::from("./merged.sqlite"),
+        help = "Merged database to create. It starts as a copy of the first input, so it must not exist yet."
+    )]
+    pub out: String,
+    #[arg(
+        long,
+        default_value_t = 6,
+        help = "Number of coarser detail levels to rebuild from the merged detail level 0, like the `lod` command does. The coarser levels of the inputs are not copied."
+    )]
+    pub lod_levels: u8,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = CompressionMode::Lzma2,
+        help = "Compression of the rebuilt section data."
+    )]
+    pub compression_mode: CompressionMode,
+    #[command(flatten)]
+    pub db: DBArgs,
 }
 
 struct GeneratingRegionInfo {
@@ -54,6 +622,7 @@ struct GeneratingRegionInfo {
 pub fn start_progressbar(
     regions_count: u64,
     out_dir: impl AsRef<Path>,
+    region_format: RegionFormat,
     status_receiver: Receiver<WorldGenStatus>,
 ) -> impl FnOnce() {
     let out_dir = out_dir.as_ref().to_path_buf();
@@ -96,8 +665,12 @@ pub fn start_progressbar(
                     WorldGenStatus::FinishDHSection { pos } => {
                         all_progress.inc(1);
                         let region_pos = RegionPos::from(pos);
-                        let region_file_path =
-                            out_dir.join(format!("r.{}.{}.mca", region_pos.x, region_pos.z));
+                        let region_file_path = out_dir.join(format!(
+                            "r.{}.{}.{}",
+                            region_pos.x,
+                            region_pos.z,
+                            region_format.extension()
+                        ));
                         let file_size = metadata(region_file_path).unwrap().len();
                         let region_info = generating_regions.get_mut(&region_pos).unwrap();
                         region_info.size = file_size;
@@ -0,0 +1,161 @@
+use std::{borrow::Cow, collections::BTreeMap, io::Write};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use serde::Serialize;
+
+use crate::{
+    chunk::AIR,
+    data::{DH_SECTION_WIDTH, DHDataRequester, RegionPos},
+};
+
+const CSV_HEADER: [&str; 10] = [
+    "section_x",
+    "section_z",
+    "column",
+    "x",
+    "z",
+    "min_y",
+    "height",
+    "block",
+    "block_state",
+    "biome",
+];
+
+/// File format of the exported column data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TableFormat {
+    /// One JSON object per line.
+    Jsonl,
+    /// Comma separated values with a header line.
+    Csv,
+}
+
+impl TableFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TableFormat::Jsonl => "jsonl",
+            TableFormat::Csv => "csv",
+        }
+    }
+}
+
+/// One decoded DH data point.
+#[derive(Debug, Serialize)]
+struct ColumnRow<'a> {
+    section_x: i32,
+    section_z: i32,
+    /// Index of the column in the section, `x * 64 + z`.
+    column: usize,
+    /// World block coordinates of the column.
+    x: i32,
+    z: i32,
+    /// World y of the lowest block of the data point.
+    min_y: i32,
+    height: i32,
+    block: &'a str,
+    block_state: BTreeMap<&'a str, &'a str>,
+    biome: &'a str,
+}
+
+impl ColumnRow<'_> {
+    fn write_csv(&self, writer: &mut impl Write) -> Result<()> {
+        let block_state = self
+            .block_state
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{}",
+            self.section_x,
+            self.section_z,
+            self.column,
+            self.x,
+            self.z,
+            self.min_y,
+            self.height,
+            csv_field(self.block),
+            csv_field(&block_state),
+            csv_field(self.biome),
+        )?;
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Writes every data point of the DH sections in `region_poses` to `writer`, one row each.
+///
+/// Regions are read one at a time, so only a single region is held in memory.
+/// Air data points are written with the block `minecraft:air`. Returns the number of rows.
+pub fn export_columns(
+    section_requester: &impl DHDataRequester,
+    region_poses: &[RegionPos],
+    format: TableFormat,
+    writer: &mut impl Write,
+    progressbar: &ProgressBar,
+) -> Result<u64> {
+    if format == TableFormat::Csv {
+        writeln!(writer, "{}", CSV_HEADER.join(","))?;
+    }
+    let mut rows = 0;
+    for region_pos in region_poses {
+        let mut dh_sections = section_requester
+            .request_sections_in_region(region_pos)?
+            .into_values()
+            .collect::<Vec<_>>();
+        dh_sections.sort_by_key(|dh_section| (dh_section.pos.x, dh_section.pos.z));
+        for dh_section in &dh_sections {
+            for (column, data_points) in dh_section.data.iter().enumerate() {
+                for data_point in data_points {
+                    let mapping = dh_section.mapping.get(data_point.id as usize);
+                    let row = ColumnRow {
+                        section_x: dh_section.pos.x,
+                        section_z: dh_section.pos.z,
+                        column,
+                        x: dh_section.pos.x * DH_SECTION_WIDTH as i32
+                            + (column / DH_SECTION_WIDTH) as i32,
+                        z: dh_section.pos.z * DH_SECTION_WIDTH as i32
+                            + (column % DH_SECTION_WIDTH) as i32,
+                        min_y: dh_section.min_y + data_point.min_y,
+                        height: data_point.height,
+                        block: mapping
+                            .and_then(|mapping| mapping.block.as_deref())
+                            .unwrap_or(AIR),
+                        block_state: mapping
+                            .map(|mapping| {
+                                mapping
+                                    .block_state
+                                    .iter()
+                                    .map(|(key, value)| (key.as_str(), value.as_str()))
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        biome: mapping.map_or("", |mapping| mapping.biome.as_str()),
+                    };
+                    match format {
+                        TableFormat::Jsonl => {
+                            serde_json::to_writer(&mut *writer, &row)?;
+                            writeln!(writer)?;
+                        }
+                        TableFormat::Csv => row.write_csv(writer)?,
+                    }
+                    rows += 1;
+                }
+            }
+        }
+        progressbar.inc(1);
+    }
+    writer.flush()?;
+    Ok(rows)
+}
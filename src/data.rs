@@ -4,7 +4,7 @@ use std::{
 };
 
 use anyhow::{Result, bail, ensure};
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::decompress::CompressionMode;
 
@@ -39,7 +39,6 @@ impl From<RegionPos> for DHSectionPos {
 }
 
 pub trait DHDataRequester {
-    #[allow(unused)]
     fn get_section_poses(&self) -> Result<Vec<DHSectionPos>>;
 
     fn request_sections_in_region(
@@ -57,26 +56,133 @@ pub struct DHSectionData {
     pub mapping: Vec<DHMappingEntry>,
     pub data_format_version: i8,
     pub compression_mode: CompressionMode,
+    /// `LastModifiedUnixDateTime` in milliseconds, `None` for sections not read from a database.
+    pub last_modified: Option<i64>,
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct DHMappingEntry {
     pub biome: String,
     pub block: Option<String>,
     pub block_state: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DHFullDataPoint {
     pub id: i32,
     pub height: i32,
     pub min_y: i32,
+    pub block_light: u8,
+    pub sky_light: u8,
 }
 
+// Bit layout of a data point, from the lowest bit: id, height, min_y, block light, sky light.
+const ID_MASK: i64 = 2147483647;
+const HEIGHT_OFFSET: i64 = 32;
+const MIN_Y_OFFSET: i64 = 44;
+const Y_MASK: i64 = 4095;
+const BLOCK_LIGHT_OFFSET: i64 = 56;
+const SKY_LIGHT_OFFSET: i64 = 60;
+const LIGHT_MASK: i64 = 15;
+
 pub const DH_SECTION_WIDTH: usize = 64;
+/// The only `DataFormatVersion` of `FullData` rows that is read and written.
+pub const DH_DATA_FORMAT_VERSION: i8 = 1;
+
+impl DHSectionData {
+    /// Highest non-air data point of the column at section-local `x`, `z`.
+    pub fn top_point(&self, x: usize, z: usize) -> Option<&DHFullDataPoint> {
+        self.data[x * DH_SECTION_WIDTH + z]
+            .iter()
+            .filter(|data_point| {
+                self.mapping
+                    .get(data_point.id as usize)
+                    .is_some_and(|mapping| mapping.block.is_some())
+            })
+            .max_by_key(|data_point| data_point.min_y + data_point.height)
+    }
+}
+
+impl DHFullDataPoint {
+    fn unpack(data: i64) -> Self {
+        DHFullDataPoint {
+            id: (data & ID_MASK) as i32,
+            height: ((data >> HEIGHT_OFFSET) & Y_MASK) as i32,
+            min_y: ((data >> MIN_Y_OFFSET) & Y_MASK) as i32,
+            block_light: ((data >> BLOCK_LIGHT_OFFSET) & LIGHT_MASK) as u8,
+            sky_light: ((data >> SKY_LIGHT_OFFSET) & LIGHT_MASK) as u8,
+        }
+    }
+
+    /// Packs the data point like `unpack` reads it, failing for fields that do not fit.
+    fn pack(&self) -> Result<i64> {
+        let fields = [
+            ("id", self.id as i64, ID_MASK),
+            ("height", self.height as i64, Y_MASK),
+            ("min_y", self.min_y as i64, Y_MASK),
+            ("block_light", self.block_light as i64, LIGHT_MASK),
+            ("sky_light", self.sky_light as i64, LIGHT_MASK),
+        ];
+        for (name, value, mask) in fields {
+            ensure!(
+                (0..=mask).contains(&value),
+                "Data point {name} [{value}] does not fit in 0..={mask}"
+            );
+        }
+        Ok(self.id as i64
+            | (self.height as i64) << HEIGHT_OFFSET
+            | (self.min_y as i64) << MIN_Y_OFFSET
+            | (self.block_light as i64) << BLOCK_LIGHT_OFFSET
+            | (self.sky_light as i64) << SKY_LIGHT_OFFSET)
+    }
+}
+
+impl DHMappingEntry {
+    /// The entry as DH stores it, `biome_DH-BSW_block_STATE_{key:value}...` or `biome_DH-BSW_AIR`.
+    /// Properties are written sorted by key, like DH does, so entries read from DH data
+    /// serialize back to the same string.
+    pub fn serialize(&self) -> String {
+        let Some(block) = &self.block else {
+            return format!("{}_DH-BSW_AIR", self.biome);
+        };
+        let mut properties = self.block_state.iter().collect::<Vec<_>>();
+        properties.sort();
+        let properties = properties
+            .into_iter()
+            .map(|(key, value)| format!("{{{key}:{value}}}"))
+            .collect::<String>();
+        format!("{}_DH-BSW_{block}_STATE_{properties}", self.biome)
+    }
 
-impl DHSectionData {}
+    /// Fails for entries that `serialize` would write ambiguously, so that they read back
+    /// differently.
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.biome.contains("_DH-BSW_"),
+            "Biome [{}] contains the separator _DH-BSW_",
+            self.biome
+        );
+        if let Some(block) = &self.block {
+            ensure!(
+                block != "AIR" && !block.contains("_STATE_"),
+                "Block [{block}] is AIR or contains the separator _STATE_"
+            );
+        }
+        for (key, value) in &self.block_state {
+            ensure!(
+                self.block.is_some(),
+                "Air in biome [{}] has the block state {key}:{value}",
+                self.biome
+            );
+            ensure!(
+                !key.contains([':', '{', '}']) && !value.contains(['{', '}']),
+                "Block state {key}:{value} contains one of the separators : {{ }}"
+            );
+        }
+        Ok(())
+    }
+}
 
 pub fn deserialize_data(
     data: Vec<u8>,
@@ -94,12 +200,7 @@ pub fn deserialize_data(
         );
         let mut data_col = Vec::<DHFullDataPoint>::new();
         for _ in 0..data_col_len {
-            let data = data.read_i64::<BigEndian>()?;
-            data_col.push(DHFullDataPoint {
-                id: (data & 2147483647i64) as i32,
-                height: ((data >> 32i64) & 4095i64) as i32,
-                min_y: ((data >> 44i64) & 4095i64) as i32,
-            });
+            data_col.push(DHFullDataPoint::unpack(data.read_i64::<BigEndian>()?));
         }
         data_list.push(data_col);
     }
@@ -116,7 +217,7 @@ pub fn deserialize_mapping(
     ensure!(state_len > 0, "There are no mapping.");
     let mut mapping = Vec::<DHMappingEntry>::new();
     for _ in 0..state_len {
-        let utf_len = data.read_i16::<BigEndian>()?;
+        let utf_len = data.read_u16::<BigEndian>()?;
         let mut buf = vec![0u8; utf_len as usize];
         data.read_exact(&mut buf)?;
         let read = String::from_utf8(buf)?;
@@ -151,11 +252,10 @@ pub fn deserialize_mapping(
             }
             let mut state_dict = HashMap::new();
             for state in states[1..states.len() - 1].split("}{") {
-                let (key, value) = {
-                    let kv: Vec<&str> = state.split(":").collect();
-                    (kv[0].to_string(), kv[1].to_string())
+                let Some((key, value)) = state.split_once(':') else {
+                    bail!("Failed to deserialize block state [{state}], unable to find separator.");
                 };
-                state_dict.insert(key, value);
+                state_dict.insert(key.to_string(), value.to_string());
             }
             mapping.push(DHMappingEntry {
                 biome,
@@ -183,3 +283,176 @@ pub fn deserialize_mapping(
     }
     Ok(mapping)
 }
+
+/// Inverse of `deserialize_data`.
+pub fn serialize_data(
+    data: &[Vec<DHFullDataPoint>],
+    compression_mode: &CompressionMode,
+) -> Result<Vec<u8>> {
+    ensure!(
+        data.len() == DH_SECTION_WIDTH * DH_SECTION_WIDTH,
+        "DataSource has {} columns, expected {}",
+        data.len(),
+        DH_SECTION_WIDTH * DH_SECTION_WIDTH
+    );
+    let mut serialized = Vec::new();
+    for data_col in data {
+        serialized.write_i16::<BigEndian>(i16::try_from(data_col.len())?)?;
+        for data_point in data_col {
+            serialized.write_i64::<BigEndian>(data_point.pack()?)?;
+        }
+    }
+    compression_mode.compress(serialized)
+}
+
+/// Inverse of `deserialize_mapping`. Entries are written with `DHMappingEntry::serialize`.
+pub fn serialize_mapping(
+    mapping: &[DHMappingEntry],
+    compression_mode: &CompressionMode,
+) -> Result<Vec<u8>> {
+    ensure!(!mapping.is_empty(), "There are no mapping.");
+    let mut serialized = Vec::new();
+    serialized.write_i32::<BigEndian>(i32::try_from(mapping.len())?)?;
+    for entry in mapping {
+        entry.validate()?;
+        let entry = entry.serialize();
+        serialized.write_u16::<BigEndian>(u16::try_from(entry.len())?)?;
+        serialized.extend_from_slice(entry.as_bytes());
+    }
+    compression_mode.compress(serialized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPRESSION_MODES: [CompressionMode; 2] =
+        [CompressionMode::Uncompressed, CompressionMode::Lzma2];
+
+    fn entry(biome: &str, block: Option<&str>, block_state: &[(&str, &str)]) -> DHMappingEntry {
+        DHMappingEntry {
+            biome: biome.to_string(),
+            block: block.map(str::to_string),
+            block_state: block_state
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn data_round_trips_at_the_edges_of_its_bit_fields() {
+        let edges = [
+            DHFullDataPoint {
+                id: 0,
+                height: 0,
+                min_y: 0,
+                block_light: 0,
+                sky_light: 0,
+            },
+            DHFullDataPoint {
+                id: i32::MAX,
+                height: 4095,
+                min_y: 4095,
+                block_light: 15,
+                sky_light: 15,
+            },
+            DHFullDataPoint {
+                id: 1 << 30,
+                height: 1 << 11,
+                min_y: 1,
+                block_light: 8,
+                sky_light: 7,
+            },
+        ];
+        let mut data = vec![Vec::new(); DH_SECTION_WIDTH * DH_SECTION_WIDTH];
+        data[0] = edges.to_vec();
+        data[4095] = vec![edges[1]; 3];
+        for compression_mode in COMPRESSION_MODES {
+            let serialized = serialize_data(&data, &compression_mode).unwrap();
+            assert_eq!(
+                deserialize_data(serialized, &compression_mode).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn data_points_outside_their_bit_fields_are_rejected() {
+        let valid = DHFullDataPoint {
+            id: 0,
+            height: 1,
+            min_y: 0,
+            block_light: 0,
+            sky_light: 15,
+        };
+        let invalid = [
+            DHFullDataPoint { id: -1, ..valid },
+            DHFullDataPoint {
+                height: 4096,
+                ..valid
+            },
+            DHFullDataPoint { min_y: -1, ..valid },
+            DHFullDataPoint {
+                sky_light: 16,
+                ..valid
+            },
+        ];
+        for data_point in invalid {
+            let mut data = vec![Vec::new(); DH_SECTION_WIDTH * DH_SECTION_WIDTH];
+            data[1] = vec![data_point];
+            assert!(serialize_data(&data, &CompressionMode::Uncompressed).is_err());
+        }
+    }
+
+    #[test]
+    fn mapping_round_trips() {
+        let mapping = vec![
+            entry("minecraft:plains", None, &[]),
+            entry("minecraft:plains", Some("minecraft:stone"), &[]),
+            entry(
+                "minecraft:ocean",
+                Some("minecraft:oak_stairs"),
+                &[
+                    ("waterlogged", "true"),
+                    ("facing", "north"),
+                    ("half", "bottom"),
+                ],
+            ),
+            entry(
+                "terralith:moonlight_grove",
+                Some("mod:sign"),
+                &[("text", "\"quoted\" a:b"), ("shape", "")],
+            ),
+        ];
+        for compression_mode in COMPRESSION_MODES {
+            let serialized = serialize_mapping(&mapping, &compression_mode).unwrap();
+            assert_eq!(
+                deserialize_mapping(serialized, &compression_mode).unwrap(),
+                mapping
+            );
+        }
+        assert_eq!(mapping[0].serialize(), "minecraft:plains_DH-BSW_AIR");
+        assert_eq!(
+            mapping[2].serialize(),
+            "minecraft:ocean_DH-BSW_minecraft:oak_stairs_STATE_{facing:north}{half:bottom}{waterlogged:true}"
+        );
+    }
+
+    #[test]
+    fn ambiguous_mappings_are_rejected() {
+        let invalid = [
+            entry("minecraft:a_DH-BSW_b", Some("minecraft:stone"), &[]),
+            entry("minecraft:plains", Some("AIR"), &[]),
+            entry("minecraft:plains", Some("mod:a_STATE_b"), &[]),
+            entry("minecraft:plains", None, &[("lit", "true")]),
+            entry("minecraft:plains", Some("minecraft:stone"), &[("a:b", "c")]),
+            entry("minecraft:plains", Some("minecraft:stone"), &[("a", "{c}")]),
+        ];
+        for entry in invalid {
+            assert!(entry.validate().is_err(), "{entry:?}");
+            let mapping = [entry];
+            assert!(serialize_mapping(&mapping, &CompressionMode::Uncompressed).is_err());
+        }
+    }
+}
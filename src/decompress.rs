@@ -1,11 +1,16 @@
-use std::io::{ErrorKind, Read};
+use std::io::{ErrorKind, Read, Write};
 
-use anyhow::{Result, anyhow};
-use xz2::bufread::XzDecoder;
+use anyhow::{Result, anyhow, bail};
+use clap::ValueEnum;
+use xz2::{bufread::XzDecoder, write::XzEncoder};
 
-#[derive(Debug)]
+/// Preset of the xz encoder, the default of the `xz` tool.
+const LZMA2_PRESET: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum CompressionMode {
     Uncompressed = 0,
+    #[value(skip)]
     Lz4 = 1,
     Lzma2 = 3,
 }
@@ -27,6 +32,18 @@ impl CompressionMode {
             CompressionMode::Lzma2 => decompress_lzma2(data),
         }
     }
+
+    pub fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            CompressionMode::Uncompressed => Ok(data),
+            CompressionMode::Lz4 => bail!("Lz4 Format is not implemented"),
+            CompressionMode::Lzma2 => {
+                let mut encoder = XzEncoder::new(Vec::new(), LZMA2_PRESET);
+                encoder.write_all(&data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
 }
 
 fn decompress_lzma2(data: Vec<u8>) -> Result<Vec<u8>> {
@@ -34,10 +51,12 @@ fn decompress_lzma2(data: Vec<u8>) -> Result<Vec<u8>> {
     let mut decomped = Vec::new();
     let err = loop {
         let mut buf = [0u8; 1];
-        if let Err(e) = decoder.read(&mut buf) {
-            break e;
+        match decoder.read(&mut buf) {
+            // A complete stream, as written by `compress`.
+            Ok(0) => return Ok(decomped),
+            Ok(_) => decomped.push(buf[0]),
+            Err(e) => break e,
         }
-        decomped.push(buf[0]);
     };
     if err.kind() == ErrorKind::UnexpectedEof {
         Ok(decomped)
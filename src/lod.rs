@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+
+use crate::{
+    data::{
+        DH_DATA_FORMAT_VERSION, DH_SECTION_WIDTH, DHFullDataPoint, DHMappingEntry, DHSectionData,
+        DHSectionPos,
+    },
+    decompress::CompressionMode,
+};
+
+/// Position of the section one detail level up that holds the section at `pos`.
+pub fn parent_pos(pos: DHSectionPos) -> DHSectionPos {
+    DHSectionPos {
+        x: pos.x.div_euclid(2),
+        z: pos.z.div_euclid(2),
+    }
+}
+
+/// Mapping of the section being built, and the ids it gave each child's mapping entries.
+struct MergedMapping<'a> {
+    mapping: Vec<DHMappingEntry>,
+    mapping_ids: HashMap<String, i32>,
+    children: Vec<(&'a DHSectionData, Vec<Option<i32>>)>,
+}
+
+impl<'a> MergedMapping<'a> {
+    fn new(children: &[&'a DHSectionData]) -> Self {
+        MergedMapping {
+            mapping: Vec::new(),
+            mapping_ids: HashMap::new(),
+            children: children
+                .iter()
+                .map(|child| (*child, vec![None; child.mapping.len()]))
+                .collect(),
+        }
+    }
+
+    /// Id in the merged mapping of entry `id` of child `child`.
+    fn id(&mut self, child: usize, id: i32) -> i32 {
+        let (section, ids) = &mut self.children[child];
+        if let Some(merged_id) = ids[id as usize] {
+            return merged_id;
+        }
+        let entry = &section.mapping[id as usize];
+        let key = entry.serialize();
+        let merged_id = match self.mapping_ids.get(&key) {
+            Some(merged_id) => *merged_id,
+            None => {
+                let merged_id = self.mapping.len() as i32;
+                self.mapping.push(DHMappingEntry {
+                    biome: entry.biome.clone(),
+                    block: entry.block.clone(),
+                    block_state: entry.block_state.clone(),
+                });
+                self.mapping_ids.insert(key, merged_id);
+                merged_id
+            }
+        };
+        ids[id as usize] = Some(merged_id);
+        merged_id
+    }
+
+    fn contains(&self, child: usize, id: i32) -> bool {
+        (0..self.children[child].1.len() as i32).contains(&id)
+    }
+}
+
+/// Builds the section at `pos` one detail level up from its four `children`, any of which may
+/// be missing. Returns `None` when all are missing.
+///
+/// This is the downsampling of DH's `FullDataSourceV2`, which fills a parent from a child one
+/// detail level below it: every column of the parent is a copy of the column at the even x
+/// and z of the 2x2 columns below it, the one nearest negative x and z. Columns over a missing
+/// child stay empty. The copied data points are rebased onto the lowest `min_y` of the
+/// children, and runs that end up next to each other with the same block and light are
+/// merged into one data point.
+pub fn downsample(
+    pos: DHSectionPos,
+    children: &HashMap<DHSectionPos, DHSectionData>,
+    compression_mode: CompressionMode,
+) -> Option<DHSectionData> {
+    // Children in x-major order, like the columns of a section.
+    let child_poses = [(0, 0), (0, 1), (1, 0), (1, 1)].map(|(dx, dz)| DHSectionPos {
+        x: pos.x * 2 + dx,
+        z: pos.z * 2 + dz,
+    });
+    let present = child_poses
+        .iter()
+        .filter_map(|child_pos| children.get(child_pos))
+        .collect::<Vec<_>>();
+    let min_y = present.iter().map(|child| child.min_y).min()?;
+    let mut mapping = MergedMapping::new(&present);
+    let child_index =
+        child_poses.map(|child_pos| present.iter().position(|child| child.pos == child_pos));
+
+    let half = DH_SECTION_WIDTH / 2;
+    let mut data = Vec::with_capacity(DH_SECTION_WIDTH * DH_SECTION_WIDTH);
+    for x in 0..DH_SECTION_WIDTH {
+        for z in 0..DH_SECTION_WIDTH {
+            let Some(child) = child_index[(x / half) * 2 + z / half] else {
+                data.push(Vec::new());
+                continue;
+            };
+            let (child_x, child_z) = ((x % half) * 2, (z % half) * 2);
+            let column = &present[child].data[child_x * DH_SECTION_WIDTH + child_z];
+            let offset = present[child].min_y - min_y;
+            data.push(copy_column(child, column, offset, &mut mapping));
+        }
+    }
+    Some(DHSectionData {
+        pos,
+        min_y,
+        data,
+        mapping: mapping.mapping,
+        data_format_version: DH_DATA_FORMAT_VERSION,
+        compression_mode,
+        last_modified: None,
+    })
+}
+
+/// Copies `column` of child `child` into the merged mapping, raising its data points by
+/// `offset` and merging adjacent data points of the same block and light.
+fn copy_column(
+    child: usize,
+    column: &[DHFullDataPoint],
+    offset: i32,
+    mapping: &mut MergedMapping,
+) -> Vec<DHFullDataPoint> {
+    let mut copied: Vec<DHFullDataPoint> = Vec::with_capacity(column.len());
+    for data_point in column {
+        if !mapping.contains(child, data_point.id) {
+            continue;
+        }
+        let data_point = DHFullDataPoint {
+            id: mapping.id(child, data_point.id),
+            min_y: data_point.min_y + offset,
+            ..*data_point
+        };
+        match copied.last_mut() {
+            Some(last)
+                if (last.id, last.block_light, last.sky_light)
+                    == (data_point.id, data_point.block_light, data_point.sky_light)
+                    && (last.min_y == data_point.min_y + data_point.height
+                        || data_point.min_y == last.min_y + last.height) =>
+            {
+                last.min_y = last.min_y.min(data_point.min_y);
+                last.height += data_point.height;
+            }
+            _ => copied.push(data_point),
+        }
+    }
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(block: Option<&str>) -> DHMappingEntry {
+        DHMappingEntry {
+            biome: "minecraft:plains".to_string(),
+            block: block.map(str::to_string),
+            block_state: HashMap::new(),
+        }
+    }
+
+    fn point(id: i32, min_y: i32, height: i32, sky_light: u8) -> DHFullDataPoint {
+        DHFullDataPoint {
+            id,
+            height,
+            min_y,
+            block_light: 0,
+            sky_light,
+        }
+    }
+
+    /// Section at `(x, z)` whose column at `(cx, cz)` is `column(cx, cz)`.
+    fn section(
+        x: i32,
+        z: i32,
+        min_y: i32,
+        mapping: Vec<DHMappingEntry>,
+        column: impl Fn(usize, usize) -> Vec<DHFullDataPoint>,
+    ) -> DHSectionData {
+        DHSectionData {
+            pos: DHSectionPos { x, z },
+            min_y,
+            data: (0..DH_SECTION_WIDTH * DH_SECTION_WIDTH)
+                .map(|i| column(i / DH_SECTION_WIDTH, i % DH_SECTION_WIDTH))
+                .collect(),
+            mapping,
+            data_format_version: DH_DATA_FORMAT_VERSION,
+            compression_mode: CompressionMode::Uncompressed,
+            last_modified: None,
+        }
+    }
+
+    fn children(sections: Vec<DHSectionData>) -> HashMap<DHSectionPos, DHSectionData> {
+        sections
+            .into_iter()
+            .map(|section| (section.pos, section))
+            .collect()
+    }
+
+    #[test]
+    fn parents_round_towards_negative_infinity() {
+        for ((x, z), (parent_x, parent_z)) in [
+            ((0, 1), (0, 0)),
+            ((2, 3), (1, 1)),
+            ((-1, -2), (-1, -1)),
+            ((-3, -4), (-2, -2)),
+            ((-5, 5), (-3, 2)),
+        ] {
+            assert_eq!(
+                parent_pos(DHSectionPos { x, z }),
+                DHSectionPos {
+                    x: parent_x,
+                    z: parent_z
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn parent_columns_copy_the_even_child_column() {
+        let stone = || vec![entry(None), entry(Some("minecraft:stone"))];
+        // Each column is as tall as its position, so the copied column can be told apart.
+        let child = |x, z| {
+            section(x, z, -64, stone(), |cx, cz| {
+                vec![
+                    point(0, (cx + cz) as i32 + 1, 10, 15),
+                    point(1, 0, (cx + cz) as i32 + 1, 0),
+                ]
+            })
+        };
+        let children = children(vec![
+            child(-2, -2),
+            child(-2, -1),
+            child(-1, -2),
+            child(-1, -1),
+        ]);
+        let parent = downsample(
+            DHSectionPos { x: -1, z: -1 },
+            &children,
+            CompressionMode::Lzma2,
+        )
+        .unwrap();
+        assert_eq!(parent.pos, DHSectionPos { x: -1, z: -1 });
+        assert_eq!(parent.min_y, -64);
+        assert_eq!(parent.mapping, stone());
+        assert_eq!(parent.compression_mode, CompressionMode::Lzma2);
+        for x in 0..DH_SECTION_WIDTH {
+            for z in 0..DH_SECTION_WIDTH {
+                let top = ((x % 32) * 2 + (z % 32) * 2) as i32 + 1;
+                assert_eq!(
+                    parent.data[x * DH_SECTION_WIDTH + z],
+                    [point(0, top, 10, 15), point(1, 0, top, 0)],
+                    "column {x}, {z}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn missing_children_leave_their_columns_empty() {
+        let mapping = || vec![entry(Some("minecraft:dirt"))];
+        let children = children(vec![section(3, 0, 0, mapping(), |_, _| {
+            vec![point(0, 0, 4, 15)]
+        })]);
+        let parent = downsample(
+            DHSectionPos { x: 1, z: 0 },
+            &children,
+            CompressionMode::Lzma2,
+        )
+        .unwrap();
+        for x in 0..DH_SECTION_WIDTH {
+            for z in 0..DH_SECTION_WIDTH {
+                let column = &parent.data[x * DH_SECTION_WIDTH + z];
+                if x >= 32 && z < 32 {
+                    assert_eq!(column, &[point(0, 0, 4, 15)]);
+                } else {
+                    assert!(column.is_empty(), "column {x}, {z}");
+                }
+            }
+        }
+        assert!(
+            downsample(
+                DHSectionPos { x: 0, z: 0 },
+                &children,
+                CompressionMode::Lzma2
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn children_are_rebased_onto_the_lowest_min_y() {
+        let mapping = || vec![entry(Some("minecraft:stone"))];
+        let children = children(vec![
+            section(0, 0, -32, mapping(), |_, _| vec![point(0, 0, 8, 15)]),
+            section(1, 1, -64, mapping(), |_, _| vec![point(0, 0, 8, 15)]),
+        ]);
+        let parent = downsample(
+            DHSectionPos { x: 0, z: 0 },
+            &children,
+            CompressionMode::Lzma2,
+        )
+        .unwrap();
+        assert_eq!(parent.min_y, -64);
+        assert_eq!(parent.data[0], [point(0, 32, 8, 15)]);
+        assert_eq!(
+            parent.data[(DH_SECTION_WIDTH - 1) * DH_SECTION_WIDTH + DH_SECTION_WIDTH - 1],
+            [point(0, 0, 8, 15)]
+        );
+    }
+
+    #[test]
+    fn runs_of_the_same_block_and_light_are_merged() {
+        // Two entries for the same block, which become one id in the parent. Ids are given in
+        // the order the entries are first used, so air becomes 0.
+        let mapping = vec![
+            entry(Some("minecraft:stone")),
+            entry(Some("minecraft:stone")),
+            entry(None),
+        ];
+        let children = children(vec![section(0, 0, 0, mapping, |_, _| {
+            vec![
+                point(2, 20, 4, 15),
+                point(2, 16, 4, 14),
+                point(0, 8, 8, 0),
+                point(1, 4, 4, 0),
+                point(0, 0, 4, 0),
+            ]
+        })]);
+        let parent = downsample(
+            DHSectionPos { x: 0, z: 0 },
+            &children,
+            CompressionMode::Lzma2,
+        )
+        .unwrap();
+        assert_eq!(parent.mapping.len(), 2);
+        assert_eq!(
+            parent.data[0],
+            [point(0, 20, 4, 15), point(0, 16, 4, 14), point(1, 0, 16, 0)]
+        );
+    }
+}
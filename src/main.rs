@@ -1,13 +1,25 @@
+mod anvil;
 mod chunk;
 mod cli;
+mod columns;
 mod data;
 mod decompress;
+mod font;
+mod lod;
+mod map;
+mod merge;
+mod mesh;
+mod packing;
+mod region;
+mod schematic;
 mod sql;
+mod volume;
 mod worldgen;
 
 use std::{
     collections::HashSet,
-    fs::create_dir_all,
+    fs::{File, create_dir_all},
+    io::BufWriter,
     path::Path,
     sync::{
         Mutex,
@@ -19,49 +31,427 @@ use anyhow::{Result, ensure};
 use clap::Parser;
 use rayon::{
     ThreadPoolBuilder,
-    iter::{IntoParallelIterator, ParallelIterator},
+    iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
 };
-use sql::DHDBConn;
+use sql::{DBOpenMode, DHDBConn};
 
 use crate::{
-    cli::{Args, start_progressbar},
-    data::RegionPos,
-    worldgen::generate,
+    anvil::{AnvilOptions, read_region, region_files},
+    chunk::{Chunk, ChunkFormat},
+    cli::{
+        Anvil2dhArgs, Args, BoxArgs, ColumnsArgs, Command, ConvertArgs, DBArgs, InputArgs,
+        LitematicArgs, LodArgs, MapArgs, MergeArgs, MeshArgs, SchemArgs, regions_progressbar,
+        start_progressbar,
+    },
+    columns::export_columns,
+    data::{DHDataRequester, RegionPos},
+    decompress::CompressionMode,
+    lod::{downsample, parent_pos},
+    map::{ColorTable, MapArea, MapOptions, render_maps},
+    merge::{MergedDB, choose_sections},
+    mesh::{ColumnRuns, Mesh},
+    region::{ChunkCompression, DEFAULT_COMPRESSION_LEVEL, RegionFormat},
+    schematic::{
+        LitematicMetadata, check_litematic_bounds, check_sponge_bounds, write_litematic,
+        write_sponge_schematic,
+    },
+    volume::{BlockBox, BlockPos, BlockVolume, read_box},
+    worldgen::{WorldGenOptions, generate},
 };
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let db_path = Path::new(&args.db_path);
-    ensure!(
-        db_path.exists(),
-        format!("DH Lod data file '{}' does not exists", args.db_path)
-    );
-    if args.threads > 0 {
+    match args.command {
+        None => convert(args.convert),
+        Some(Command::Map(args)) => map(args),
+        Some(Command::Schem(args)) => schem(args),
+        Some(Command::Litematic(args)) => litematic(args),
+        Some(Command::Mesh(args)) => mesh(args),
+        Some(Command::Columns(args)) => columns(args),
+        Some(Command::Anvil2dh(args)) => anvil2dh(args),
+        Some(Command::Lod(args)) => lod(args),
+        Some(Command::Merge(args)) => merge(args),
+    }
+}
+
+fn open_conns(args: &DBArgs) -> Result<Vec<DHDBConn>> {
+    let mut conns = Vec::with_capacity(args.db_paths.len());
+    for db_path in &args.db_paths {
+        let path = Path::new(db_path);
+        ensure!(
+            path.exists(),
+            format!("DH Lod data file '{db_path}' does not exists")
+        );
+        conns.push(DHDBConn::get_conn(path, args.db_mode)?);
+    }
+    Ok(conns)
+}
+
+fn open_db(args: &DBArgs) -> Result<MergedDB> {
+    Ok(MergedDB::new(open_conns(args)?, args.overlap))
+}
+
+fn set_threads(threads: u8) {
+    if threads > 0 {
         ThreadPoolBuilder::new()
-            .num_threads(args.threads as usize)
+            .num_threads(threads as usize)
             .build_global()
             .unwrap();
     }
-    let conn = DHDBConn::get_conn(db_path)?;
+}
+
+/// Whether `pos` is selected by a `--range` of `range` regions, 0 selecting all.
+fn in_range(pos: &RegionPos, range: u32) -> bool {
+    let limit = range as i64;
+    range == 0
+        || (-limit..limit).contains(&(pos.x as i64)) && (-limit..limit).contains(&(pos.z as i64))
+}
+
+/// Opens the input database and lists the regions selected by `args.range`.
+fn open_input(args: &InputArgs) -> Result<(MergedDB, Vec<RegionPos>)> {
+    set_threads(args.threads);
+    let conn = open_db(&args.db)?;
     let region_poses: Vec<_> = conn
         .get_section_poses()?
         .into_par_iter()
         .map(RegionPos::from)
-        .filter(|pos| {
-            let limit = args.range as i64;
-            args.range == 0
-                || (-limit..limit).contains(&(pos.x as i64))
-                    && (-limit..limit).contains(&(pos.z as i64))
-        })
+        .filter(|pos| in_range(pos, args.range))
         .collect::<HashSet<_>>()
         .into_iter()
         .collect::<Vec<_>>();
-    let conn = Mutex::new(conn);
+    Ok((conn, region_poses))
+}
+
+fn convert(args: ConvertArgs) -> Result<()> {
+    if let Some(level) = args.compression_level
+        && args.region_format == RegionFormat::Anvil
+    {
+        ensure!(
+            matches!(
+                args.chunk_compression,
+                ChunkCompression::Zlib | ChunkCompression::Gzip
+            ),
+            "`--compression-level` only applies to zlib and gzip chunks and Linear regions, not to `--chunk-compression none` or `lz4`"
+        );
+        ensure!(
+            level <= 9,
+            "Compression level {level} is only supported by `--region-format linear`, zlib and gzip go up to 9"
+        );
+    }
+    let format = ChunkFormat::new(
+        args.format.data_version,
+        args.format.min_y,
+        args.format.height,
+    )?;
+    let template = args
+        .chunk_template
+        .as_ref()
+        .map(|template| Chunk::load_template(template, &format))
+        .transpose()?;
+    let (conn, region_poses) = open_input(&args.input)?;
     let out_dir = Path::new(&args.out);
     create_dir_all(out_dir)?;
     let (status_sender, status_receiver) = mpsc::channel();
-    let stop_progressbar = start_progressbar(region_poses.len() as u64, out_dir, status_receiver);
-    generate(region_poses, conn, out_dir, status_sender)?;
+    let stop_progressbar = start_progressbar(
+        region_poses.len() as u64,
+        out_dir,
+        args.region_format,
+        status_receiver,
+    );
+    let options = WorldGenOptions {
+        biome_sampling: args.biome_sampling,
+        fluid_ticks: args.fluid_ticks,
+        filler: args.filler,
+        status: args.chunk_status,
+        format,
+        template,
+        region_format: args.region_format,
+        compression: args.chunk_compression,
+        compression_level: args.compression_level.unwrap_or(DEFAULT_COMPRESSION_LEVEL),
+    };
+    generate(region_poses, conn, out_dir, &options, status_sender)?;
     stop_progressbar();
     Ok(())
 }
+
+fn map(args: MapArgs) -> Result<()> {
+    let mut block_colors = ColorTable::blocks();
+    if let Some(file) = &args.block_colors {
+        block_colors.load(file)?;
+    }
+    let mut biome_colors = ColorTable::biomes();
+    if let Some(file) = &args.biome_colors {
+        biome_colors.load(file)?;
+    }
+    let mut kinds = args.kinds;
+    kinds.sort();
+    kinds.dedup();
+    let area = args
+        .from
+        .zip(args.to)
+        .map(|(from, to)| MapArea::new(from, to));
+    let (conn, mut region_poses) = open_input(&args.input)?;
+    if let Some(area) = &area {
+        region_poses.retain(|region_pos| area.overlaps_region(region_pos));
+    }
+    let out_dir = Path::new(&args.out);
+    create_dir_all(out_dir)?;
+    let progressbar = regions_progressbar(region_poses.len() as u64);
+    let options = MapOptions {
+        kinds,
+        stitch: args.stitch,
+        area,
+        block_colors,
+        biome_colors,
+        height: args.height,
+    };
+    render_maps(region_poses, conn, out_dir, &options, progressbar)?;
+    println!("Done ✨");
+    Ok(())
+}
+
+/// Reconstructs the box of blocks selected by `args`.
+fn read_volume(args: &BoxArgs) -> Result<BlockVolume> {
+    let format = ChunkFormat::new(
+        args.format.data_version,
+        args.format.min_y,
+        args.format.height,
+    )?;
+    let conn = open_db(&args.db)?;
+    let options = WorldGenOptions {
+        filler: args.filler.clone(),
+        format,
+        ..Default::default()
+    };
+    BlockVolume::read(&conn, BlockBox::new(args.from, args.to), &options)
+}
+
+fn schem(args: SchemArgs) -> Result<()> {
+    check_sponge_bounds(&BlockBox::new(args.area.from, args.area.to))?;
+    let volume = read_volume(&args.area)?;
+    write_sponge_schematic(&volume, args.area.format.data_version, &args.out)?;
+    println!("Done ✨");
+    Ok(())
+}
+
+/// Name of a Litematica schematic whose output file has no name to take it from.
+const DEFAULT_LITEMATIC_NAME: &str = "Unnamed";
+
+fn litematic(args: LitematicArgs) -> Result<()> {
+    check_litematic_bounds(&BlockBox::new(args.area.from, args.area.to))?;
+    ensure!(
+        args.name.as_ref().is_none_or(|name| !name.is_empty()),
+        "The schematic name must not be empty"
+    );
+    let name = args.name.clone().unwrap_or_else(|| {
+        Path::new(&args.out)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .filter(|stem| !stem.is_empty())
+            .unwrap_or_else(|| DEFAULT_LITEMATIC_NAME.to_string())
+    });
+    let volume = read_volume(&args.area)?;
+    let metadata = LitematicMetadata {
+        name,
+        author: args.author,
+        description: args.description,
+    };
+    write_litematic(&volume, &metadata, args.area.format.data_version, &args.out)?;
+    println!("Done ✨");
+    Ok(())
+}
+
+fn mesh(args: MeshArgs) -> Result<()> {
+    let format = ChunkFormat::new(
+        args.format.data_version,
+        args.format.min_y,
+        args.format.height,
+    )?;
+    let mut colors = ColorTable::blocks();
+    if let Some(file) = &args.block_colors {
+        colors.load(file)?;
+    }
+    let options = WorldGenOptions {
+        filler: args.filler,
+        format,
+        ..Default::default()
+    };
+    let out_dir = Path::new(&args.out);
+    create_dir_all(out_dir)?;
+    let extension = args.mesh_format.extension();
+    let write_mesh = |conn: &MergedDB, bounds: BlockBox, file_name: String| {
+        let mut runs = ColumnRuns::new(bounds);
+        read_box(conn, &bounds, &options, &mut runs)?;
+        Mesh::new(runs).write(args.mesh_format, &colors, &out_dir.join(file_name))
+    };
+    if let (Some(from), Some(to)) = (args.from, args.to) {
+        let conn = open_db(&args.input.db)?;
+        write_mesh(
+            &conn,
+            BlockBox::new(from, to),
+            format!("terrain.{extension}"),
+        )?;
+    } else {
+        let (conn, region_poses) = open_input(&args.input)?;
+        let progressbar = regions_progressbar(region_poses.len() as u64);
+        region_poses
+            .into_par_iter()
+            .try_for_each(|region_pos| -> Result<()> {
+                let bounds = BlockBox::new(
+                    BlockPos {
+                        x: region_pos.x * 512,
+                        y: format.min_y,
+                        z: region_pos.z * 512,
+                    },
+                    BlockPos {
+                        x: region_pos.x * 512 + 511,
+                        y: format.min_y + format.section_count as i32 * 16 - 1,
+                        z: region_pos.z * 512 + 511,
+                    },
+                );
+                write_mesh(
+                    &conn,
+                    bounds,
+                    format!("r.{}.{}.{extension}", region_pos.x, region_pos.z),
+                )?;
+                progressbar.inc(1);
+                Ok(())
+            })?;
+        progressbar.finish_with_message("Finished");
+    }
+    println!("Done ✨");
+    Ok(())
+}
+
+fn columns(args: ColumnsArgs) -> Result<()> {
+    let out = args
+        .out
+        .unwrap_or_else(|| format!("./columns.{}", args.table_format.extension()));
+    let (conn, mut region_poses) = open_input(&args.input)?;
+    region_poses.sort_by_key(|pos| (pos.x, pos.z));
+    let mut writer = BufWriter::new(File::create(&out)?);
+    let progressbar = regions_progressbar(region_poses.len() as u64);
+    let rows = export_columns(
+        &conn,
+        &region_poses,
+        args.table_format,
+        &mut writer,
+        &progressbar,
+    )?;
+    progressbar.finish_with_message(format!("{rows} rows"));
+    println!("Done ✨");
+    Ok(())
+}
+
+fn anvil2dh(args: Anvil2dhArgs) -> Result<()> {
+    ensure!(
+        0 < args.height && args.height <= 4096,
+        "World height {} is not in 1-4096, the heights DH data can store",
+        args.height
+    );
+    set_threads(args.threads);
+    let options = AnvilOptions {
+        min_y: args.min_y,
+        height: args.height,
+        compression_mode: args.compression_mode,
+    };
+    let regions = region_files(&args.region_dir)?
+        .into_iter()
+        .filter(|(pos, _)| in_range(pos, args.range))
+        .collect::<Vec<_>>();
+    let conn = Mutex::new(DHDBConn::create(&args.out)?);
+    let progressbar = regions_progressbar(regions.len() as u64);
+    regions
+        .into_par_iter()
+        .try_for_each(|(region_pos, path)| -> Result<()> {
+            let sections = read_region(&path, region_pos, &options)?;
+            conn.lock()
+                .expect("Failed to lock DHDBConn it is poisoned")
+                .put_sections(0, &sections)?;
+            progressbar.inc(1);
+            Ok(())
+        })?;
+    progressbar.finish_with_message("Finished");
+    generate_detail_levels(&conn, args.lod_levels, args.compression_mode)?;
+    println!("Done ✨");
+    Ok(())
+}
+
+fn lod(args: LodArgs) -> Result<()> {
+    ensure!(
+        Path::new(&args.db_path).exists(),
+        "DH Lod data file '{}' does not exists",
+        args.db_path
+    );
+    set_threads(args.threads);
+    let conn = match &args.out {
+        Some(out) => {
+            DHDBConn::get_conn(&args.db_path, DBOpenMode::ReadOnly)?.backup_to(out)?;
+            DHDBConn::get_conn(out, DBOpenMode::ReadWrite)?
+        }
+        None => DHDBConn::get_conn(&args.db_path, DBOpenMode::ReadWrite)?,
+    };
+    let conn = Mutex::new(conn);
+    generate_detail_levels(&conn, args.levels, args.compression_mode)?;
+    println!("Done ✨");
+    Ok(())
+}
+
+/// Number of sections downsampled before they are written in one transaction.
+const LOD_BATCH_SIZE: usize = 256;
+
+/// Regenerates detail levels 1 to `levels`, each from the level below it.
+fn generate_detail_levels(
+    conn: &Mutex<DHDBConn>,
+    levels: u8,
+    compression_mode: CompressionMode,
+) -> Result<()> {
+    let lock = || conn.lock().expect("Failed to lock DHDBConn it is poisoned");
+    for detail_level in 1..=levels {
+        let mut parent_poses = lock()
+            .get_detail_level_poses(detail_level - 1)?
+            .into_iter()
+            .map(parent_pos)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        parent_poses.sort_by_key(|pos| (pos.x, pos.z));
+        let progressbar = regions_progressbar(parent_poses.len() as u64);
+        progressbar.set_message(format!("Detail level {detail_level}"));
+        for batch in parent_poses.chunks(LOD_BATCH_SIZE) {
+            let sections = batch
+                .par_iter()
+                .map(|pos| -> Result<_> {
+                    let children = lock().get_sections_in_area(
+                        detail_level - 1,
+                        pos.x * 2..pos.x * 2 + 2,
+                        pos.z * 2..pos.z * 2 + 2,
+                    )?;
+                    progressbar.inc(1);
+                    Ok(downsample(*pos, &children, compression_mode))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let sections = sections.into_iter().flatten().collect::<Vec<_>>();
+            lock().put_sections(detail_level, &sections)?;
+        }
+        progressbar.finish_with_message(format!("Detail level {detail_level} finished"));
+    }
+    Ok(())
+}
+
+fn merge(args: MergeArgs) -> Result<()> {
+    let conns = open_conns(&args.db)?;
+    let chosen = choose_sections(&conns, args.db.overlap)?;
+    conns[0].backup_to(&args.out)?;
+    let merged = DHDBConn::create(&args.out)?;
+    merged.delete_detail_levels_above(0)?;
+    for (i, (keys, db_path)) in chosen.iter().zip(&args.db.db_paths).enumerate() {
+        if i > 0 {
+            merged.copy_rows(&conns[i], keys)?;
+        }
+        println!("{} sections from '{db_path}'", keys.len());
+    }
+    generate_detail_levels(&Mutex::new(merged), args.lod_levels, args.compression_mode)?;
+    println!("Done ✨");
+    Ok(())
+}
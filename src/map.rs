@@ -0,0 +1,718 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{File, read_to_string},
+    io::BufWriter,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use xxhash_rust::xxh64::xxh64;
+
+use crate::{
+    data::{DH_SECTION_WIDTH, DHDataRequester, DHSectionPos, RegionPos},
+    font::{GLYPH_HEIGHT, GLYPH_WIDTH, glyph},
+    worldgen::SECTION_REGION_SCALE,
+};
+
+const REGION_WIDTH: usize = SECTION_REGION_SCALE * DH_SECTION_WIDTH;
+/// Largest stitched map in pixels, 8192x8192. Each map kind is held in memory as RGBA.
+const MAX_STITCHED_PIXELS: usize = 1 << 26;
+
+/// Colors of common top blocks, roughly their look from above.
+const BLOCK_COLORS: [(&str, [u8; 3]); 48] = [
+    ("minecraft:grass_block", [0x7f, 0xb2, 0x38]),
+    ("minecraft:dirt", [0x97, 0x6d, 0x4d]),
+    ("minecraft:coarse_dirt", [0x77, 0x55, 0x3b]),
+    ("minecraft:podzol", [0x5a, 0x3f, 0x1c]),
+    ("minecraft:mycelium", [0x6f, 0x63, 0x69]),
+    ("minecraft:mud", [0x3c, 0x39, 0x3d]),
+    ("minecraft:dirt_path", [0x94, 0x7a, 0x41]),
+    ("minecraft:farmland", [0x8f, 0x66, 0x46]),
+    ("minecraft:stone", [0x70, 0x70, 0x70]),
+    ("minecraft:deepslate", [0x4d, 0x4d, 0x50]),
+    ("minecraft:andesite", [0x88, 0x88, 0x88]),
+    ("minecraft:diorite", [0xbc, 0xbc, 0xbc]),
+    ("minecraft:granite", [0x95, 0x67, 0x55]),
+    ("minecraft:tuff", [0x6c, 0x6d, 0x66]),
+    ("minecraft:calcite", [0xdf, 0xe0, 0xdc]),
+    ("minecraft:gravel", [0x83, 0x7f, 0x7e]),
+    ("minecraft:sand", [0xf7, 0xe9, 0xa3]),
+    ("minecraft:sandstone", [0xd8, 0xcb, 0x9b]),
+    ("minecraft:red_sand", [0xbe, 0x66, 0x21]),
+    ("minecraft:red_sandstone", [0xb5, 0x62, 0x1f]),
+    ("minecraft:terracotta", [0x98, 0x5e, 0x43]),
+    ("minecraft:clay", [0xa4, 0xa8, 0xb8]),
+    ("minecraft:water", [0x40, 0x40, 0xff]),
+    ("minecraft:lava", [0xff, 0x60, 0x00]),
+    ("minecraft:ice", [0xa0, 0xa0, 0xff]),
+    ("minecraft:packed_ice", [0x8d, 0xb4, 0xfa]),
+    ("minecraft:blue_ice", [0x74, 0xa7, 0xfd]),
+    ("minecraft:snow", [0xff, 0xff, 0xff]),
+    ("minecraft:snow_block", [0xf9, 0xfe, 0xfe]),
+    ("minecraft:powder_snow", [0xf8, 0xfd, 0xfd]),
+    ("minecraft:oak_leaves", [0x48, 0x7d, 0x20]),
+    ("minecraft:spruce_leaves", [0x3d, 0x62, 0x3d]),
+    ("minecraft:birch_leaves", [0x6a, 0x8f, 0x44]),
+    ("minecraft:jungle_leaves", [0x37, 0x8a, 0x1b]),
+    ("minecraft:acacia_leaves", [0x4f, 0x7a, 0x17]),
+    ("minecraft:dark_oak_leaves", [0x2f, 0x5d, 0x16]),
+    ("minecraft:mangrove_leaves", [0x4a, 0x83, 0x20]),
+    ("minecraft:cherry_leaves", [0xe5, 0xad, 0xc2]),
+    ("minecraft:azalea_leaves", [0x5a, 0x73, 0x2c]),
+    ("minecraft:short_grass", [0x7f, 0xb2, 0x38]),
+    ("minecraft:moss_block", [0x59, 0x6e, 0x2d]),
+    ("minecraft:netherrack", [0x70, 0x02, 0x00]),
+    ("minecraft:soul_sand", [0x51, 0x3e, 0x32]),
+    ("minecraft:soul_soil", [0x4b, 0x39, 0x2e]),
+    ("minecraft:basalt", [0x49, 0x49, 0x4f]),
+    ("minecraft:crimson_nylium", [0x85, 0x1f, 0x1f]),
+    ("minecraft:warped_nylium", [0x2b, 0x72, 0x65]),
+    ("minecraft:end_stone", [0xdb, 0xde, 0x9e]),
+];
+
+/// Colors of the vanilla biomes, close to the palette of common map viewers.
+const BIOME_COLORS: [(&str, [u8; 3]); 65] = [
+    ("minecraft:ocean", [0x00, 0x00, 0x70]),
+    ("minecraft:deep_ocean", [0x00, 0x00, 0x30]),
+    ("minecraft:warm_ocean", [0x00, 0x00, 0xac]),
+    ("minecraft:lukewarm_ocean", [0x00, 0x00, 0x90]),
+    ("minecraft:deep_lukewarm_ocean", [0x00, 0x00, 0x40]),
+    ("minecraft:cold_ocean", [0x20, 0x20, 0x70]),
+    ("minecraft:deep_cold_ocean", [0x20, 0x20, 0x38]),
+    ("minecraft:frozen_ocean", [0x70, 0x70, 0xd6]),
+    ("minecraft:deep_frozen_ocean", [0x40, 0x40, 0x90]),
+    ("minecraft:river", [0x00, 0x00, 0xff]),
+    ("minecraft:frozen_river", [0xa0, 0xa0, 0xff]),
+    ("minecraft:beach", [0xfa, 0xde, 0x55]),
+    ("minecraft:snowy_beach", [0xfa, 0xf0, 0xc0]),
+    ("minecraft:stony_shore", [0xa2, 0xa2, 0x84]),
+    ("minecraft:plains", [0x8d, 0xb3, 0x60]),
+    ("minecraft:sunflower_plains", [0xb5, 0xdb, 0x88]),
+    ("minecraft:snowy_plains", [0xff, 0xff, 0xff]),
+    ("minecraft:ice_spikes", [0xb4, 0xdc, 0xdc]),
+    ("minecraft:desert", [0xfa, 0x94, 0x18]),
+    ("minecraft:swamp", [0x07, 0xf9, 0xb2]),
+    ("minecraft:mangrove_swamp", [0x2c, 0xcc, 0x8e]),
+    ("minecraft:forest", [0x05, 0x66, 0x21]),
+    ("minecraft:flower_forest", [0x2d, 0x8e, 0x49]),
+    ("minecraft:birch_forest", [0x30, 0x74, 0x44]),
+    ("minecraft:old_growth_birch_forest", [0x58, 0x9c, 0x6c]),
+    ("minecraft:dark_forest", [0x40, 0x51, 0x1a]),
+    ("minecraft:pale_garden", [0x69, 0x6d, 0x67]),
+    ("minecraft:taiga", [0x0b, 0x66, 0x59]),
+    ("minecraft:old_growth_pine_taiga", [0x59, 0x66, 0x51]),
+    ("minecraft:old_growth_spruce_taiga", [0x81, 0x8e, 0x79]),
+    ("minecraft:snowy_taiga", [0x31, 0x55, 0x4a]),
+    ("minecraft:savanna", [0xbd, 0xb2, 0x5f]),
+    ("minecraft:savanna_plateau", [0xa7, 0x9d, 0x64]),
+    ("minecraft:windswept_savanna", [0xe5, 0xda, 0x87]),
+    ("minecraft:windswept_hills", [0x60, 0x60, 0x60]),
+    ("minecraft:windswept_gravelly_hills", [0x88, 0x88, 0x88]),
+    ("minecraft:windswept_forest", [0x50, 0x70, 0x50]),
+    ("minecraft:jungle", [0x53, 0x7b, 0x09]),
+    ("minecraft:sparse_jungle", [0x62, 0x8b, 0x17]),
+    ("minecraft:bamboo_jungle", [0x76, 0x8e, 0x14]),
+    ("minecraft:badlands", [0xd9, 0x45, 0x15]),
+    ("minecraft:eroded_badlands", [0xff, 0x6d, 0x3d]),
+    ("minecraft:wooded_badlands", [0xb0, 0x97, 0x65]),
+    ("minecraft:meadow", [0x83, 0xbb, 0x6d]),
+    ("minecraft:cherry_grove", [0xff, 0xb7, 0xdf]),
+    ("minecraft:grove", [0x8d, 0xa7, 0xa0]),
+    ("minecraft:snowy_slopes", [0xc4, 0xc4, 0xc4]),
+    ("minecraft:frozen_peaks", [0xa0, 0xa0, 0xd0]),
+    ("minecraft:jagged_peaks", [0xdc, 0xdc, 0xc8]),
+    ("minecraft:stony_peaks", [0xb0, 0xb0, 0xb0]),
+    ("minecraft:mushroom_fields", [0xff, 0x00, 0xff]),
+    ("minecraft:dripstone_caves", [0x7b, 0x6a, 0x4b]),
+    ("minecraft:lush_caves", [0x28, 0x3c, 0x00]),
+    ("minecraft:deep_dark", [0x0f, 0x2a, 0x30]),
+    ("minecraft:nether_wastes", [0xbf, 0x3b, 0x3b]),
+    ("minecraft:crimson_forest", [0xdd, 0x08, 0x08]),
+    ("minecraft:warped_forest", [0x49, 0x90, 0x7b]),
+    ("minecraft:soul_sand_valley", [0x5e, 0x38, 0x30]),
+    ("minecraft:basalt_deltas", [0x40, 0x36, 0x36]),
+    ("minecraft:the_end", [0x80, 0x80, 0xff]),
+    ("minecraft:end_highlands", [0xb5, 0xb5, 0x36]),
+    ("minecraft:end_midlands", [0xd5, 0xd5, 0xa4]),
+    ("minecraft:small_end_islands", [0x4b, 0x4b, 0xab]),
+    ("minecraft:end_barrens", [0x70, 0x70, 0xcc]),
+    ("minecraft:the_void", [0x00, 0x00, 0x00]),
+];
+
+const LEGEND_ROW_HEIGHT: usize = 12;
+const LEGEND_BACKGROUND: [u8; 3] = [0x20, 0x20, 0x20];
+const LEGEND_TEXT: [u8; 3] = [0xff, 0xff, 0xff];
+
+/// Kind of overview map that is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum MapKind {
+    /// Grayscale height of the highest block of each column.
+    Height,
+    /// Color of the highest block of each column.
+    Blocks,
+    /// Color of the biome of the highest block of each column, with a legend.
+    Biomes,
+}
+
+impl MapKind {
+    fn name(&self) -> &'static str {
+        match self {
+            MapKind::Height => "height",
+            MapKind::Blocks => "blocks",
+            MapKind::Biomes => "biomes",
+        }
+    }
+}
+
+/// Map colors of block or biome ids.
+#[derive(Debug, Clone)]
+pub struct ColorTable {
+    colors: HashMap<String, [u8; 3]>,
+}
+
+impl ColorTable {
+    fn new(colors: &[(&str, [u8; 3])]) -> Self {
+        ColorTable {
+            colors: colors
+                .iter()
+                .map(|(id, color)| (id.to_string(), *color))
+                .collect(),
+        }
+    }
+
+    pub fn blocks() -> Self {
+        ColorTable::new(&BLOCK_COLORS)
+    }
+
+    pub fn biomes() -> Self {
+        ColorTable::new(&BIOME_COLORS)
+    }
+
+    /// Overrides colors with the `<id> <#rrggbb>` lines of `file`.
+    pub fn load(&mut self, file: impl AsRef<Path>) -> Result<()> {
+        let file = file.as_ref();
+        let text = read_to_string(file)
+            .with_context(|| format!("Failed to read colors '{}'", file.display()))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, color) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [id, color] => (id, color),
+                _ => bail!(
+                    "{}:{}: expected `<id> <#rrggbb>`, found '{line}'",
+                    file.display(),
+                    i + 1
+                ),
+            };
+            let color = parse_color(color)
+                .with_context(|| format!("{}:{}: invalid color", file.display(), i + 1))?;
+            let id = if id.contains(':') {
+                id.to_string()
+            } else {
+                format!("minecraft:{id}")
+            };
+            self.colors.insert(id, color);
+        }
+        Ok(())
+    }
+
+    /// Color of `id`, or a stable color derived from it for unlisted ids.
+    pub fn color(&self, id: &str) -> [u8; 3] {
+        if let Some(color) = self.colors.get(id) {
+            return *color;
+        }
+        let [r, g, b, ..] = xxh64(id.as_bytes(), 0).to_le_bytes();
+        [r, g, b]
+    }
+}
+
+fn parse_color(color: &str) -> Result<[u8; 3]> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    ensure!(
+        hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        "'{color}' is not a `#rrggbb` color"
+    );
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok([channel(0), channel(2), channel(4)])
+}
+
+/// A block column position in world coordinates, parsed from `x,z`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnPos {
+    pub x: i32,
+    pub z: i32,
+}
+
+impl FromStr for ColumnPos {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords = s
+            .split(',')
+            .map(|coord| {
+                coord
+                    .trim()
+                    .parse::<i32>()
+                    .with_context(|| format!("'{coord}' is not a block coordinate"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        match coords[..] {
+            [x, z] => Ok(ColumnPos { x, z }),
+            _ => bail!("Expected a block column position as `x,z`, found '{s}'"),
+        }
+    }
+}
+
+/// A rectangle of block columns, both corners included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapArea {
+    pub min: ColumnPos,
+    pub max: ColumnPos,
+}
+
+impl MapArea {
+    /// The area spanned by two opposite corners in any order.
+    pub fn new(a: ColumnPos, b: ColumnPos) -> Self {
+        MapArea {
+            min: ColumnPos {
+                x: a.x.min(b.x),
+                z: a.z.min(b.z),
+            },
+            max: ColumnPos {
+                x: a.x.max(b.x),
+                z: a.z.max(b.z),
+            },
+        }
+    }
+
+    /// The area covered by the regions at `region_poses`, `None` if there are none.
+    fn of_regions(region_poses: &[RegionPos]) -> Option<Self> {
+        let width = REGION_WIDTH as i32;
+        let min_x = region_poses.iter().map(|pos| pos.x).min()?;
+        let max_x = region_poses.iter().map(|pos| pos.x).max()?;
+        let min_z = region_poses.iter().map(|pos| pos.z).min()?;
+        let max_z = region_poses.iter().map(|pos| pos.z).max()?;
+        Some(MapArea {
+            min: ColumnPos {
+                x: min_x * width,
+                z: min_z * width,
+            },
+            max: ColumnPos {
+                x: max_x * width + width - 1,
+                z: max_z * width + width - 1,
+            },
+        })
+    }
+
+    /// Whether the region at `pos` holds any column of the area.
+    pub fn overlaps_region(&self, pos: &RegionPos) -> bool {
+        let width = REGION_WIDTH as i32;
+        pos.x * width <= self.max.x
+            && self.min.x < (pos.x + 1) * width
+            && pos.z * width <= self.max.z
+            && self.min.z < (pos.z + 1) * width
+    }
+
+    /// Whether the column at `x`, `z` is in the area.
+    fn contains(&self, x: i32, z: i32) -> bool {
+        (self.min.x..=self.max.x).contains(&x) && (self.min.z..=self.max.z).contains(&z)
+    }
+
+    fn width(&self) -> usize {
+        (self.max.x - self.min.x) as usize + 1
+    }
+
+    fn height(&self) -> usize {
+        (self.max.z - self.min.z) as usize + 1
+    }
+}
+
+/// Settings for rendering overview maps.
+#[derive(Debug, Clone)]
+pub struct MapOptions {
+    pub kinds: Vec<MapKind>,
+    /// Renders one image covering all regions instead of one per region.
+    pub stitch: bool,
+    /// Area of the stitched image, by default the area of all rendered regions.
+    pub area: Option<MapArea>,
+    pub block_colors: ColorTable,
+    pub biome_colors: ColorTable,
+    /// World height in blocks, the heightmap goes from black at its bottom to white at its top.
+    pub height: u32,
+}
+
+/// An RGBA image, transparent where there is no data.
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        let i = (y * self.width + x) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&[r, g, b, 0xff]);
+    }
+
+    /// Copies `image` with its top left corner at `x`, `y`, clipped to this image.
+    fn draw(&mut self, image: &Image, x: isize, y: isize) {
+        let columns = x.max(0)..(x + image.width as isize).min(self.width as isize);
+        if columns.is_empty() {
+            return;
+        }
+        let rows = y.max(0)..(y + image.height as isize).min(self.height as isize);
+        for row in rows {
+            let src = (((row - y) * image.width as isize + columns.start - x) * 4) as usize;
+            let dst = ((row * self.width as isize + columns.start) * 4) as usize;
+            let len = columns.len() * 4;
+            self.pixels[dst..dst + len].copy_from_slice(&image.pixels[src..src + len]);
+        }
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: [u8; 3]) {
+        for y in y..y + height {
+            for x in x..x + width {
+                self.set(x, y, color);
+            }
+        }
+    }
+
+    /// Draws `text` with its top left corner at `x`, `y`, without clipping.
+    fn text(&mut self, x: usize, y: usize, text: &str, color: [u8; 3]) {
+        for (i, char) in text.chars().enumerate() {
+            let glyph = glyph(char);
+            for (row, bits) in glyph.iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits >> (GLYPH_WIDTH - 1 - column) & 1 == 1 {
+                        self.set(x + i * (GLYPH_WIDTH + 1) + column, y + row, color);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns this image with a legend of `entries` to its right, a color swatch and name per row.
+    fn with_legend(&self, entries: &[(&str, [u8; 3])]) -> Image {
+        let text_width = entries
+            .iter()
+            .map(|(name, _)| name.chars().count())
+            .max()
+            .unwrap_or(0)
+            * (GLYPH_WIDTH + 1);
+        let legend_width = LEGEND_ROW_HEIGHT * 2 + text_width;
+        let legend_height = (entries.len() * LEGEND_ROW_HEIGHT + 4).max(self.height);
+        let mut image = Image::new(self.width + legend_width, legend_height);
+        image.draw(self, 0, 0);
+        image.fill(
+            self.width,
+            0,
+            legend_width,
+            legend_height,
+            LEGEND_BACKGROUND,
+        );
+        for (i, (name, color)) in entries.iter().enumerate() {
+            let y = 4 + i * LEGEND_ROW_HEIGHT;
+            image.fill(
+                self.width + 4,
+                y,
+                LEGEND_ROW_HEIGHT - 3,
+                LEGEND_ROW_HEIGHT - 3,
+                *color,
+            );
+            image.text(
+                self.width + LEGEND_ROW_HEIGHT + 6,
+                y + (LEGEND_ROW_HEIGHT - 3 - GLYPH_HEIGHT) / 2,
+                name,
+                LEGEND_TEXT,
+            );
+        }
+        image
+    }
+
+    fn save(&self, file: impl AsRef<Path>) -> Result<()> {
+        let file = BufWriter::new(File::create(file)?);
+        let mut encoder = png::Encoder::new(file, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Maps of one region, one image per kind in `MapOptions::kinds`.
+struct RegionMaps {
+    images: Vec<Image>,
+    /// Biomes shown on the biome map.
+    biomes: BTreeSet<String>,
+}
+
+/// Renders the maps of `options.kinds` into `out_dir`.
+///
+/// Each region becomes a 512x512 image with x to the right and z downwards, saved as
+/// `<kind>.r.<x>.<z>.png`. With `options.stitch` the regions are drawn straight into one
+/// `<kind>.png` covering `options.area`, which is refused above `MAX_STITCHED_PIXELS`.
+/// Biome maps get a legend of the biomes they show on their right side.
+pub fn render_maps(
+    region_poses: Vec<RegionPos>,
+    section_requester: impl DHDataRequester + Send + Sync,
+    out_dir: impl AsRef<Path>,
+    options: &MapOptions,
+    progressbar: ProgressBar,
+) -> Result<()> {
+    let out_dir = out_dir.as_ref();
+    ensure!(
+        out_dir.is_dir(),
+        "{} is not a directory",
+        out_dir.to_str().unwrap_or("None")
+    );
+    let region_poses = region_poses
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    if options.stitch {
+        if let Some(area) = options.area.or_else(|| MapArea::of_regions(&region_poses)) {
+            stitch_maps(
+                region_poses,
+                &section_requester,
+                area,
+                out_dir,
+                options,
+                &progressbar,
+            )?;
+        }
+    } else {
+        region_poses
+            .into_par_iter()
+            .try_for_each(|region_pos| -> Result<()> {
+                let maps = render_region(region_pos, &section_requester, None, options)?;
+                progressbar.inc(1);
+                for (kind, image) in options.kinds.iter().zip(&maps.images) {
+                    save_map(
+                        *kind,
+                        image,
+                        &maps.biomes,
+                        options,
+                        out_dir.join(format!(
+                            "{}.r.{}.{}.png",
+                            kind.name(),
+                            region_pos.x,
+                            region_pos.z
+                        )),
+                    )?;
+                }
+                Ok(())
+            })?;
+    }
+    progressbar.finish_with_message("Finished");
+    Ok(())
+}
+
+/// Renders the regions at `region_poses` into one image per kind covering `area`.
+fn stitch_maps(
+    region_poses: Vec<RegionPos>,
+    section_requester: &(impl DHDataRequester + Send + Sync),
+    area: MapArea,
+    out_dir: &Path,
+    options: &MapOptions,
+    progressbar: &ProgressBar,
+) -> Result<()> {
+    let (width, height) = (area.width(), area.height());
+    ensure!(
+        width.saturating_mul(height) <= MAX_STITCHED_PIXELS,
+        "A stitched map of {width}x{height} pixels is larger than the limit of {MAX_STITCHED_PIXELS} pixels. Select a smaller area with `--from` and `--to`, or leave out `--stitch` for one image per region."
+    );
+    let stitched = Mutex::new(RegionMaps {
+        images: options
+            .kinds
+            .iter()
+            .map(|_| Image::new(width, height))
+            .collect(),
+        biomes: BTreeSet::new(),
+    });
+    region_poses
+        .into_par_iter()
+        .try_for_each(|region_pos| -> Result<()> {
+            let maps = render_region(region_pos, section_requester, Some(&area), options)?;
+            let x = (region_pos.x as isize * REGION_WIDTH as isize) - area.min.x as isize;
+            let z = (region_pos.z as isize * REGION_WIDTH as isize) - area.min.z as isize;
+            let mut stitched = stitched.lock().expect("Stitched maps are poisoned");
+            for (image, region_image) in stitched.images.iter_mut().zip(&maps.images) {
+                image.draw(region_image, x, z);
+            }
+            stitched.biomes.extend(maps.biomes);
+            progressbar.inc(1);
+            Ok(())
+        })?;
+    let stitched = stitched.into_inner().expect("Stitched maps are poisoned");
+    for (kind, image) in options.kinds.iter().zip(&stitched.images) {
+        save_map(
+            *kind,
+            image,
+            &stitched.biomes,
+            options,
+            out_dir.join(format!("{}.png", kind.name())),
+        )?;
+    }
+    Ok(())
+}
+
+fn save_map(
+    kind: MapKind,
+    image: &Image,
+    biomes: &BTreeSet<String>,
+    options: &MapOptions,
+    file: impl AsRef<Path>,
+) -> Result<()> {
+    if kind != MapKind::Biomes {
+        return image.save(file);
+    }
+    let entries = biomes
+        .iter()
+        .map(|biome| {
+            let name = biome.strip_prefix("minecraft:").unwrap_or(biome);
+            (name, options.biome_colors.color(biome))
+        })
+        .collect::<Vec<_>>();
+    image.with_legend(&entries).save(file)
+}
+
+/// Renders the maps of `options.kinds` for the region at `region_pos`. Only biomes of columns
+/// in `area` are collected for the legend, the whole region is drawn either way.
+fn render_region(
+    region_pos: RegionPos,
+    section_requester: &impl DHDataRequester,
+    area: Option<&MapArea>,
+    options: &MapOptions,
+) -> Result<RegionMaps> {
+    let mut maps = RegionMaps {
+        images: options
+            .kinds
+            .iter()
+            .map(|_| Image::new(REGION_WIDTH, REGION_WIDTH))
+            .collect(),
+        biomes: BTreeSet::new(),
+    };
+    let region_snapped_section_pos = DHSectionPos::from(region_pos);
+    let dh_sections = section_requester.request_sections_in_region(&region_pos)?;
+    for (section_pos, dh_section) in &dh_sections {
+        let offset_x = (section_pos.x - region_snapped_section_pos.x) as usize * DH_SECTION_WIDTH;
+        let offset_z = (section_pos.z - region_snapped_section_pos.z) as usize * DH_SECTION_WIDTH;
+        for x in 0..DH_SECTION_WIDTH {
+            for z in 0..DH_SECTION_WIDTH {
+                let Some(top_point) = dh_section.top_point(x, z) else {
+                    continue;
+                };
+                let mapping = &dh_section.mapping[top_point.id as usize];
+                let in_area = area.is_none_or(|area| {
+                    area.contains(
+                        region_pos.x * REGION_WIDTH as i32 + (offset_x + x) as i32,
+                        region_pos.z * REGION_WIDTH as i32 + (offset_z + z) as i32,
+                    )
+                });
+                for (kind, image) in options.kinds.iter().zip(&mut maps.images) {
+                    let color = match kind {
+                        MapKind::Height => {
+                            let top = (top_point.min_y + top_point.height) as u32;
+                            let gray = (top.min(options.height) * 255 / options.height) as u8;
+                            [gray; 3]
+                        }
+                        MapKind::Blocks => options
+                            .block_colors
+                            .color(mapping.block.as_deref().unwrap_or_default()),
+                        MapKind::Biomes => {
+                            if in_area && !maps.biomes.contains(&mapping.biome) {
+                                maps.biomes.insert(mapping.biome.clone());
+                            }
+                            options.biome_colors.color(&mapping.biome)
+                        }
+                    };
+                    image.set(offset_x + x, offset_z + z, color);
+                }
+            }
+        }
+    }
+    Ok(maps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn areas_select_overlapping_regions() {
+        let area = MapArea::new(ColumnPos { x: 600, z: -1 }, ColumnPos { x: -3, z: -600 });
+        let overlapping = [(-1, -2), (-1, -1), (0, -2), (0, -1), (1, -2), (1, -1)];
+        for x in -3..3 {
+            for z in -3..3 {
+                assert_eq!(
+                    area.overlaps_region(&RegionPos { x, z }),
+                    overlapping.contains(&(x, z)),
+                    "region {x} {z}"
+                );
+            }
+        }
+        assert_eq!((area.width(), area.height()), (604, 600));
+        assert!(area.contains(-3, -600) && area.contains(600, -1));
+        assert!(!area.contains(-4, -300) && !area.contains(0, 0));
+    }
+
+    #[test]
+    fn draw_clips_to_the_image() {
+        let mut image = Image::new(4, 3);
+        let mut region = Image::new(3, 3);
+        region.fill(0, 0, 3, 3, [1, 2, 3]);
+        image.draw(&region, -2, 1);
+        image.draw(&region, 10, 0);
+        let opaque = |x: usize, y: usize| image.pixels[(y * 4 + x) * 4 + 3] == 0xff;
+        assert!(opaque(0, 1) && opaque(0, 2));
+        assert!(!opaque(1, 1) && !opaque(0, 0));
+    }
+
+    #[test]
+    fn legends_list_entries_right_of_the_map() {
+        let mut map = Image::new(20, 10);
+        map.fill(0, 0, 20, 10, [9, 9, 9]);
+        let image = map.with_legend(&[("ab", [1, 2, 3]), ("abc", [4, 5, 6])]);
+        let legend_width = LEGEND_ROW_HEIGHT * 2 + 3 * (GLYPH_WIDTH + 1);
+        assert_eq!(image.width, 20 + legend_width);
+        assert_eq!(image.height, 2 * LEGEND_ROW_HEIGHT + 4);
+        let pixel = |x: usize, y: usize| {
+            let i = (y * image.width + x) * 4;
+            [image.pixels[i], image.pixels[i + 1], image.pixels[i + 2]]
+        };
+        // The map keeps its pixels and is transparent below its bottom.
+        assert_eq!(pixel(19, 9), [9, 9, 9]);
+        assert_eq!(image.pixels[(12 * image.width) * 4 + 3], 0);
+        // One swatch per row, next to its name.
+        assert_eq!(pixel(24, 4), [1, 2, 3]);
+        assert_eq!(pixel(24, 4 + LEGEND_ROW_HEIGHT), [4, 5, 6]);
+        assert_eq!(pixel(24 + LEGEND_ROW_HEIGHT, 4), LEGEND_BACKGROUND);
+        let text_x = 20 + LEGEND_ROW_HEIGHT + 6;
+        let text_y = 4 + (LEGEND_ROW_HEIGHT - 3 - GLYPH_HEIGHT) / 2;
+        for (row, bits) in glyph('a').iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                let lit = bits >> (GLYPH_WIDTH - 1 - column) & 1 == 1;
+                let expected = if lit { LEGEND_TEXT } else { LEGEND_BACKGROUND };
+                assert_eq!(pixel(text_x + column, text_y + row), expected);
+            }
+        }
+    }
+}
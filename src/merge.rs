@@ -0,0 +1,156 @@
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    data::{DHDataRequester, DHSectionData, DHSectionPos, RegionPos},
+    sql::DHDBConn,
+};
+
+/// Which section is kept when several databases store one at the same position.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OverlapPolicy {
+    /// The section modified last, by its `LastModifiedUnixDateTime`.
+    #[default]
+    Newest,
+    /// The section with the most columns holding data, then the most data points.
+    MostComplete,
+    /// The section of the database given first.
+    First,
+}
+
+impl OverlapPolicy {
+    /// Whether `candidate` replaces `kept`, which comes from a database given earlier.
+    pub fn prefers(&self, candidate: &DHSectionData, kept: &DHSectionData) -> bool {
+        match self {
+            OverlapPolicy::Newest => candidate.last_modified > kept.last_modified,
+            OverlapPolicy::MostComplete => completeness(candidate) > completeness(kept),
+            OverlapPolicy::First => false,
+        }
+    }
+}
+
+/// Number of columns with data and number of data points of `section`.
+fn completeness(section: &DHSectionData) -> (usize, usize) {
+    let columns = section.data.iter().filter(|column| !column.is_empty());
+    (columns.clone().count(), columns.map(Vec::len).sum())
+}
+
+/// Several DH databases read as one, resolving sections stored in more than one by a policy.
+pub struct MergedDB {
+    conns: Vec<Mutex<DHDBConn>>,
+    policy: OverlapPolicy,
+}
+
+impl MergedDB {
+    pub fn new(conns: Vec<DHDBConn>, policy: OverlapPolicy) -> Self {
+        MergedDB {
+            conns: conns.into_iter().map(Mutex::new).collect(),
+            policy,
+        }
+    }
+}
+
+/// Adds `sections` to `merged`, keeping the section `policy` prefers at each position.
+pub fn merge_sections(
+    merged: &mut HashMap<DHSectionPos, DHSectionData>,
+    sections: HashMap<DHSectionPos, DHSectionData>,
+    policy: OverlapPolicy,
+) {
+    for (pos, section) in sections {
+        match merged.entry(pos) {
+            Entry::Occupied(mut kept) => {
+                if policy.prefers(&section, kept.get()) {
+                    kept.insert(section);
+                }
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(section);
+            }
+        }
+    }
+}
+
+impl DHDataRequester for MergedDB {
+    fn get_section_poses(&self) -> Result<Vec<DHSectionPos>> {
+        let mut poses = HashSet::new();
+        for conn in &self.conns {
+            poses.extend(conn.get_section_poses()?);
+        }
+        Ok(poses.into_iter().collect())
+    }
+
+    fn request_sections_in_region(
+        &self,
+        pos: &RegionPos,
+    ) -> Result<HashMap<DHSectionPos, DHSectionData>> {
+        let mut sections = HashMap::new();
+        for conn in &self.conns {
+            merge_sections(
+                &mut sections,
+                conn.request_sections_in_region(pos)?,
+                self.policy,
+            );
+        }
+        Ok(sections)
+    }
+}
+
+/// Decides which database each section of detail level 0 is taken from when merging `conns`.
+///
+/// Returns the detail levels and positions to take from each database, in the order of
+/// `conns`. Sections stored in only one database are always taken from it. The coarser
+/// detail levels are not chosen, they are rebuilt from the chosen sections so that they
+/// never show another database than the sections below them.
+pub fn choose_sections(
+    conns: &[DHDBConn],
+    policy: OverlapPolicy,
+) -> Result<Vec<Vec<(u8, DHSectionPos)>>> {
+    let load = |i: usize, detail_level: u8, pos: DHSectionPos| {
+        conns[i]
+            .get_sections_in_area(detail_level, pos.x..pos.x + 1, pos.z..pos.z + 1)?
+            .remove(&pos)
+            .with_context(|| format!("Section {pos:?} disappeared from database {i}"))
+    };
+    // Database and last modification of the section kept at each detail level and position.
+    let mut kept = HashMap::<(u8, DHSectionPos), (usize, Option<i64>)>::new();
+    for (i, conn) in conns.iter().enumerate() {
+        for (detail_level, pos, last_modified) in conn.get_section_keys()? {
+            if detail_level != 0 {
+                continue;
+            }
+            match kept.entry((detail_level, pos)) {
+                Entry::Occupied(mut entry) => {
+                    let (kept_i, kept_last_modified) = *entry.get();
+                    // Only sections compared by their data are read.
+                    let replace = match policy {
+                        OverlapPolicy::Newest => last_modified > kept_last_modified,
+                        OverlapPolicy::MostComplete => policy.prefers(
+                            &load(i, detail_level, pos)?,
+                            &load(kept_i, detail_level, pos)?,
+                        ),
+                        OverlapPolicy::First => false,
+                    };
+                    if replace {
+                        entry.insert((i, last_modified));
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert((i, last_modified));
+                }
+            }
+        }
+    }
+    let mut chosen = vec![Vec::new(); conns.len()];
+    for (key, (i, _)) in kept {
+        chosen[i].push(key);
+    }
+    for keys in &mut chosen {
+        keys.sort_by_key(|(detail_level, pos)| (*detail_level, pos.x, pos.z));
+    }
+    Ok(chosen)
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::Path,
+};
+
+use anyhow::{Result, ensure};
+use byteorder::{LittleEndian, WriteBytesExt};
+use clap::ValueEnum;
+use serde_json::json;
+
+use crate::{
+    chunk::{AIR, ChunkPalette},
+    map::ColorTable,
+    volume::BlockBox,
+    worldgen::ColumnTarget,
+};
+
+const GLB_MAGIC: u32 = 0x4654_6c67;
+const GLB_VERSION: u32 = 2;
+const GLB_CHUNK_JSON: u32 = 0x4e4f_534a;
+const GLB_CHUNK_BIN: u32 = 0x004e_4942;
+const GLTF_FLOAT: u32 = 5126;
+const GLTF_UNSIGNED_INT: u32 = 5125;
+const GLTF_ARRAY_BUFFER: u32 = 34962;
+const GLTF_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+/// File format of exported meshes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MeshFormat {
+    /// Wavefront `.obj` with a `.mtl` material library next to it.
+    Obj,
+    /// Binary glTF 2.0 `.glb`.
+    Glb,
+}
+
+impl MeshFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MeshFormat::Obj => "obj",
+            MeshFormat::Glb => "glb",
+        }
+    }
+}
+
+/// A solid run of one block id in a column.
+#[derive(Debug, Clone)]
+struct Run {
+    ys: Range<i32>,
+    material: u16,
+}
+
+/// The solid runs of every column of a box, with the block ids as materials.
+#[derive(Debug)]
+pub struct ColumnRuns {
+    bounds: BlockBox,
+    // Indexed by `z * width + x` relative to the box minimum, sorted by height once finished.
+    columns: Vec<Vec<Run>>,
+    materials: Vec<String>,
+    material_ids: HashMap<String, u16>,
+}
+
+impl ColumnRuns {
+    pub fn new(bounds: BlockBox) -> Self {
+        ColumnRuns {
+            bounds,
+            columns: vec![Vec::new(); bounds.width() * bounds.length()],
+            materials: Vec::new(),
+            material_ids: HashMap::new(),
+        }
+    }
+
+    /// Sorts the runs of each column and merges touching runs of the same block.
+    fn finish(&mut self) {
+        for column in &mut self.columns {
+            column.sort_by_key(|run| run.ys.start);
+            let mut merged: Vec<Run> = Vec::with_capacity(column.len());
+            for run in column.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if last.ys.end == run.ys.start && last.material == run.material => {
+                        last.ys.end = run.ys.end;
+                    }
+                    _ => merged.push(run),
+                }
+            }
+            *column = merged;
+        }
+    }
+
+    /// Runs of the column at box-relative `x`, `z`, empty outside of the box.
+    fn column(&self, x: i32, z: i32) -> &[Run] {
+        if x < 0 || z < 0 || x >= self.bounds.width() as i32 || z >= self.bounds.length() as i32 {
+            return &[];
+        }
+        &self.columns[z as usize * self.bounds.width() + x as usize]
+    }
+}
+
+impl ColumnTarget for ColumnRuns {
+    /// Records a run at box-relative `x`, `z`, the parts of `ys` outside the box are ignored.
+    fn fill_column(
+        &mut self,
+        x: u32,
+        z: u32,
+        ys: Range<i32>,
+        block: u16,
+        _biome: u16,
+        palette: &ChunkPalette,
+    ) -> Result<()> {
+        ensure!(
+            (x as usize) < self.bounds.width() && (z as usize) < self.bounds.length(),
+            "x or z is out of bounds x:{x} z:{z}"
+        );
+        let ys = ys.start.max(self.bounds.min.y)..ys.end.min(self.bounds.max.y + 1);
+        let name = &palette.block(block).name;
+        if ys.is_empty() || name == AIR {
+            return Ok(());
+        }
+        let material = match self.material_ids.get(name) {
+            Some(id) => *id,
+            None => {
+                let id = self.materials.len() as u16;
+                self.materials.push(name.clone());
+                self.material_ids.insert(name.clone(), id);
+                id
+            }
+        };
+        self.columns[z as usize * self.bounds.width() + x as usize].push(Run { ys, material });
+        Ok(())
+    }
+
+    /// Meshes have no scheduled ticks.
+    fn schedule_fluid_tick(&mut self, _x: u32, _y: i32, _z: u32, _fluid: &str) {}
+}
+
+/// Direction a face points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Face {
+    Up,
+    Down,
+    North,
+    South,
+    West,
+    East,
+}
+
+impl Face {
+    fn normal(&self) -> [f32; 3] {
+        match self {
+            Face::Up => [0.0, 1.0, 0.0],
+            Face::Down => [0.0, -1.0, 0.0],
+            Face::North => [0.0, 0.0, -1.0],
+            Face::South => [0.0, 0.0, 1.0],
+            Face::West => [-1.0, 0.0, 0.0],
+            Face::East => [1.0, 0.0, 0.0],
+        }
+    }
+}
+
+/// A rectangle with its corners in counterclockwise order seen from the front.
+#[derive(Debug, Clone, Copy)]
+struct Quad {
+    corners: [[i32; 3]; 4],
+    face: Face,
+}
+
+impl Quad {
+    /// The `face` side of the blocks at world `x`, `z` over the height range `ys`.
+    fn new(x: i32, z: i32, ys: Range<i32>, face: Face) -> Self {
+        let (y0, y1) = (ys.start, ys.end);
+        let corners = match face {
+            Face::Up => [
+                [x, y1, z],
+                [x, y1, z + 1],
+                [x + 1, y1, z + 1],
+                [x + 1, y1, z],
+            ],
+            Face::Down => [
+                [x, y0, z],
+                [x + 1, y0, z],
+                [x + 1, y0, z + 1],
+                [x, y0, z + 1],
+            ],
+            Face::North => [[x, y0, z], [x, y1, z], [x + 1, y1, z], [x + 1, y0, z]],
+            Face::South => [
+                [x, y0, z + 1],
+                [x + 1, y0, z + 1],
+                [x + 1, y1, z + 1],
+                [x, y1, z + 1],
+            ],
+            Face::West => [[x, y0, z], [x, y0, z + 1], [x, y1, z + 1], [x, y1, z]],
+            Face::East => [
+                [x + 1, y0, z],
+                [x + 1, y1, z],
+                [x + 1, y1, z + 1],
+                [x + 1, y0, z + 1],
+            ],
+        };
+        Quad { corners, face }
+    }
+}
+
+/// Visible faces of the terrain in world coordinates, grouped by block id.
+#[derive(Debug)]
+pub struct Mesh {
+    materials: Vec<String>,
+    quads: Vec<Vec<Quad>>,
+}
+
+impl Mesh {
+    /// Builds the faces of `runs` that are not covered by another run.
+    ///
+    /// Faces at the sides of the box are kept, since the columns past it are unknown.
+    pub fn new(mut runs: ColumnRuns) -> Self {
+        runs.finish();
+        let mut quads = vec![Vec::new(); runs.materials.len()];
+        let neighbors = [
+            (Face::North, 0, -1),
+            (Face::South, 0, 1),
+            (Face::West, -1, 0),
+            (Face::East, 1, 0),
+        ];
+        for z in 0..runs.bounds.length() as i32 {
+            for x in 0..runs.bounds.width() as i32 {
+                let block_x = runs.bounds.min.x + x;
+                let block_z = runs.bounds.min.z + z;
+                let column = runs.column(x, z);
+                for (i, run) in column.iter().enumerate() {
+                    let material_quads = &mut quads[run.material as usize];
+                    if column
+                        .get(i + 1)
+                        .is_none_or(|above| above.ys.start != run.ys.end)
+                    {
+                        material_quads.push(Quad::new(block_x, block_z, run.ys.clone(), Face::Up));
+                    }
+                    if i == 0 || column[i - 1].ys.end != run.ys.start {
+                        material_quads.push(Quad::new(
+                            block_x,
+                            block_z,
+                            run.ys.clone(),
+                            Face::Down,
+                        ));
+                    }
+                    for (face, dx, dz) in neighbors {
+                        for ys in uncovered(&run.ys, runs.column(x + dx, z + dz)) {
+                            material_quads.push(Quad::new(block_x, block_z, ys, face));
+                        }
+                    }
+                }
+            }
+        }
+        Mesh {
+            materials: runs.materials,
+            quads,
+        }
+    }
+
+    /// Writes the mesh to `file`, and for OBJ its material library next to it.
+    pub fn write(&self, format: MeshFormat, colors: &ColorTable, file: &Path) -> Result<()> {
+        match format {
+            MeshFormat::Obj => self.write_obj(colors, file),
+            MeshFormat::Glb => self.write_glb(colors, file),
+        }
+    }
+
+    fn write_obj(&self, colors: &ColorTable, file: &Path) -> Result<()> {
+        let mtl_file = file.with_extension("mtl");
+        let mut mtl = BufWriter::new(File::create(&mtl_file)?);
+        for material in &self.materials {
+            let [r, g, b] = colors.color(material).map(|c| c as f32 / 255.0);
+            writeln!(mtl, "newmtl {}", material.replace(':', "_"))?;
+            writeln!(mtl, "Kd {r:.4} {g:.4} {b:.4}")?;
+        }
+        mtl.flush()?;
+
+        let mut obj = BufWriter::new(File::create(file)?);
+        let mtl_name = mtl_file.file_name().unwrap_or_default().to_string_lossy();
+        writeln!(obj, "mtllib {mtl_name}")?;
+        let faces = [
+            Face::Up,
+            Face::Down,
+            Face::North,
+            Face::South,
+            Face::West,
+            Face::East,
+        ];
+        for face in faces {
+            let [x, y, z] = face.normal();
+            writeln!(obj, "vn {x} {y} {z}")?;
+        }
+        // OBJ indices start at 1.
+        let mut vertex_ids = HashMap::new();
+        for quads in &self.quads {
+            for quad in quads {
+                for corner in quad.corners {
+                    if !vertex_ids.contains_key(&corner) {
+                        vertex_ids.insert(corner, vertex_ids.len() + 1);
+                        let [x, y, z] = corner;
+                        writeln!(obj, "v {x} {y} {z}")?;
+                    }
+                }
+            }
+        }
+        for (material, quads) in self.materials.iter().zip(&self.quads) {
+            writeln!(obj, "usemtl {}", material.replace(':', "_"))?;
+            for quad in quads {
+                let normal = faces.iter().position(|face| *face == quad.face).unwrap() + 1;
+                let [a, b, c, d] = quad.corners.map(|corner| vertex_ids[&corner]);
+                writeln!(
+                    obj,
+                    "f {a}//{normal} {b}//{normal} {c}//{normal} {d}//{normal}"
+                )?;
+            }
+        }
+        obj.flush()?;
+        Ok(())
+    }
+
+    fn write_glb(&self, colors: &ColorTable, file: &Path) -> Result<()> {
+        let mut bin = Vec::new();
+        let mut buffer_views = Vec::new();
+        let mut accessors = Vec::new();
+        let mut primitives = Vec::new();
+        let mut materials = Vec::new();
+        for (material, quads) in self.materials.iter().zip(&self.quads) {
+            if quads.is_empty() {
+                continue;
+            }
+            // Vertices are shared by quads with the same normal only.
+            let mut vertex_ids = HashMap::new();
+            let mut positions = Vec::new();
+            let mut normals = Vec::new();
+            let mut indices = Vec::new();
+            for quad in quads {
+                let ids = quad.corners.map(|corner| {
+                    *vertex_ids.entry((corner, quad.face)).or_insert_with(|| {
+                        positions.push(corner.map(|c| c as f32));
+                        normals.push(quad.face.normal());
+                        positions.len() as u32 - 1
+                    })
+                });
+                indices.extend_from_slice(&[ids[0], ids[1], ids[2], ids[0], ids[2], ids[3]]);
+            }
+            let min = (0..3)
+                .map(|i| positions.iter().map(|p| p[i]).fold(f32::INFINITY, f32::min))
+                .collect::<Vec<_>>();
+            let max = (0..3)
+                .map(|i| {
+                    positions
+                        .iter()
+                        .map(|p| p[i])
+                        .fold(f32::NEG_INFINITY, f32::max)
+                })
+                .collect::<Vec<_>>();
+            let position = push_view(&mut bin, &mut buffer_views, GLTF_ARRAY_BUFFER, |bin| {
+                for value in positions.iter().flatten() {
+                    bin.write_f32::<LittleEndian>(*value)?;
+                }
+                Ok(())
+            })?;
+            let normal = push_view(&mut bin, &mut buffer_views, GLTF_ARRAY_BUFFER, |bin| {
+                for value in normals.iter().flatten() {
+                    bin.write_f32::<LittleEndian>(*value)?;
+                }
+                Ok(())
+            })?;
+            let index = push_view(
+                &mut bin,
+                &mut buffer_views,
+                GLTF_ELEMENT_ARRAY_BUFFER,
+                |bin| {
+                    for value in &indices {
+                        bin.write_u32::<LittleEndian>(*value)?;
+                    }
+                    Ok(())
+                },
+            )?;
+            accessors.push(json!({
+                "bufferView": position,
+                "componentType": GLTF_FLOAT,
+                "count": positions.len(),
+                "type": "VEC3",
+                "min": min,
+                "max": max,
+            }));
+            accessors.push(json!({
+                "bufferView": normal,
+                "componentType": GLTF_FLOAT,
+                "count": normals.len(),
+                "type": "VEC3",
+            }));
+            accessors.push(json!({
+                "bufferView": index,
+                "componentType": GLTF_UNSIGNED_INT,
+                "count": indices.len(),
+                "type": "SCALAR",
+            }));
+            let [r, g, b] = colors.color(material).map(|c| c as f32 / 255.0);
+            materials.push(json!({
+                "name": material,
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": [r, g, b, 1.0],
+                    "metallicFactor": 0.0,
+                    "roughnessFactor": 1.0,
+                },
+            }));
+            primitives.push(json!({
+                "attributes": {
+                    "POSITION": accessors.len() - 3,
+                    "NORMAL": accessors.len() - 2,
+                },
+                "indices": accessors.len() - 1,
+                "material": materials.len() - 1,
+            }));
+        }
+        let mut gltf = json!({
+            "asset": { "version": "2.0", "generator": "dh2anvil" },
+            "scene": 0,
+            "scenes": [{ "nodes": [] }],
+        });
+        if !primitives.is_empty() {
+            gltf["scenes"][0]["nodes"] = json!([0]);
+            gltf["nodes"] = json!([{ "mesh": 0 }]);
+            gltf["meshes"] = json!([{ "primitives": primitives }]);
+            gltf["materials"] = json!(materials);
+            gltf["accessors"] = json!(accessors);
+            gltf["bufferViews"] = json!(buffer_views);
+            gltf["buffers"] = json!([{ "byteLength": bin.len() }]);
+        }
+        let mut json = serde_json::to_vec(&gltf)?;
+        json.resize(json.len().next_multiple_of(4), b' ');
+
+        let mut glb = BufWriter::new(File::create(file)?);
+        let bin_chunk_len = if bin.is_empty() { 0 } else { 8 + bin.len() };
+        glb.write_u32::<LittleEndian>(GLB_MAGIC)?;
+        glb.write_u32::<LittleEndian>(GLB_VERSION)?;
+        glb.write_u32::<LittleEndian>((12 + 8 + json.len() + bin_chunk_len) as u32)?;
+        glb.write_u32::<LittleEndian>(json.len() as u32)?;
+        glb.write_u32::<LittleEndian>(GLB_CHUNK_JSON)?;
+        glb.write_all(&json)?;
+        if !bin.is_empty() {
+            glb.write_u32::<LittleEndian>(bin.len() as u32)?;
+            glb.write_u32::<LittleEndian>(GLB_CHUNK_BIN)?;
+            glb.write_all(&bin)?;
+        }
+        glb.flush()?;
+        Ok(())
+    }
+}
+
+/// Appends the data written by `write` to `bin` as a new buffer view and returns its index.
+/// All data is made of 4 byte values, so views stay aligned.
+fn push_view(
+    bin: &mut Vec<u8>,
+    buffer_views: &mut Vec<serde_json::Value>,
+    target: u32,
+    write: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>,
+) -> Result<usize> {
+    let offset = bin.len();
+    write(bin)?;
+    buffer_views.push(json!({
+        "buffer": 0,
+        "byteOffset": offset,
+        "byteLength": bin.len() - offset,
+        "target": target,
+    }));
+    Ok(buffer_views.len() - 1)
+}
+
+/// Parts of `ys` not covered by the sorted, disjoint `runs`.
+fn uncovered(ys: &Range<i32>, runs: &[Run]) -> Vec<Range<i32>> {
+    let mut parts = Vec::new();
+    let mut start = ys.start;
+    for run in runs {
+        if run.ys.end <= start {
+            continue;
+        }
+        if ys.end <= run.ys.start {
+            break;
+        }
+        if start < run.ys.start {
+            parts.push(start..run.ys.start);
+        }
+        start = start.max(run.ys.end);
+    }
+    if start < ys.end {
+        parts.push(start..ys.end);
+    }
+    parts
+}
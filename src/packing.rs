@@ -0,0 +1,186 @@
+use anyhow::{Result, ensure};
+
+/// How entries are laid out in a long array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packing {
+    /// Entries never span two longs, the unused high bits of each long are zero.
+    /// Used by chunk sections since Minecraft 1.16.
+    Padded,
+    /// Entries are packed back to back and may span two longs.
+    /// Used by chunk sections before 1.16 and by Litematica.
+    Spanning,
+}
+
+/// Number of bits per entry for a palette of `palette_len` entries, at least `min_bits`.
+///
+/// Chunk sections store their palette on disk even when it grows past the 8 bits where the
+/// network protocol switches to global ids, so this keeps growing up to 12 bits for a full
+/// 16x16x16 section of distinct entries.
+pub fn bits_per_entry(palette_len: usize, min_bits: u32) -> u32 {
+    (usize::BITS - palette_len.saturating_sub(1).leading_zeros()).max(min_bits)
+}
+
+/// Number of longs needed to store `len` entries of `bits` bits.
+pub fn packed_len(len: usize, bits: u32, packing: Packing) -> usize {
+    if bits == 0 {
+        return 0;
+    }
+    match packing {
+        Packing::Padded => len.div_ceil((u64::BITS / bits) as usize),
+        Packing::Spanning => (len * bits as usize).div_ceil(u64::BITS as usize),
+    }
+}
+
+pub fn pack_data(data: &[u16], bits: u32, packing: Packing) -> Vec<i64> {
+    if bits == 0 {
+        return Vec::new();
+    }
+    let mut packed_data = vec![0u64; packed_len(data.len(), bits, packing)];
+    let mask = (1u64 << bits) - 1;
+    match packing {
+        Packing::Padded => {
+            let entries_per_long = (u64::BITS / bits) as usize;
+            for (i, data_item) in data.iter().enumerate() {
+                let shift = (i % entries_per_long) as u32 * bits;
+                packed_data[i / entries_per_long] |= (*data_item as u64 & mask) << shift;
+            }
+        }
+        Packing::Spanning => {
+            for (i, data_item) in data.iter().enumerate() {
+                let data_item = *data_item as u64 & mask;
+                let bit = i * bits as usize;
+                let (long, shift) = (bit / 64, (bit % 64) as u32);
+                packed_data[long] |= data_item << shift;
+                if shift + bits > u64::BITS {
+                    packed_data[long + 1] |= data_item >> (u64::BITS - shift);
+                }
+            }
+        }
+    }
+    packed_data.into_iter().map(|long| long as i64).collect()
+}
+
+/// Unpacks exactly `len` entries, ignoring the padding after the last one.
+pub fn unpack_data(data: &[i64], bits: u32, len: usize, packing: Packing) -> Result<Vec<u16>> {
+    if bits == 0 {
+        return Ok(vec![0; len]);
+    }
+    let expected_len = packed_len(len, bits, packing);
+    ensure!(
+        data.len() == expected_len,
+        "Packed data has {} longs, but {expected_len} are needed for {len} entries of {bits} bits",
+        data.len()
+    );
+    let mask = (1u64 << bits) - 1;
+    let mut unpacked_data = Vec::with_capacity(len);
+    match packing {
+        Packing::Padded => {
+            let entries_per_long = (u64::BITS / bits) as usize;
+            for i in 0..len {
+                let shift = (i % entries_per_long) as u32 * bits;
+                let long = data[i / entries_per_long] as u64;
+                unpacked_data.push((long >> shift & mask) as u16);
+            }
+        }
+        Packing::Spanning => {
+            for i in 0..len {
+                let bit = i * bits as usize;
+                let (long, shift) = (bit / 64, (bit % 64) as u32);
+                let mut data_item = data[long] as u64 >> shift;
+                if shift + bits > u64::BITS {
+                    data_item |= (data[long + 1] as u64) << (u64::BITS - shift);
+                }
+                unpacked_data.push((data_item & mask) as u16);
+            }
+        }
+    }
+    Ok(unpacked_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKINGS: [Packing; 2] = [Packing::Padded, Packing::Spanning];
+
+    /// Deterministic entries below `1 << bits`, from a xorshift generator.
+    fn random_entries(len: usize, bits: u32, seed: u64) -> Vec<u16> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state & ((1 << bits) - 1)) as u16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_every_bits_per_entry() {
+        for packing in PACKINGS {
+            for bits in 1..=15 {
+                for len in [1, 63, 64, 65, 4095, 4096] {
+                    let data = random_entries(len, bits, bits as u64 * 7919 + len as u64);
+                    let packed = pack_data(&data, bits, packing);
+                    assert_eq!(packed.len(), packed_len(len, bits, packing));
+                    let unpacked = unpack_data(&packed, bits, len, packing).unwrap();
+                    assert_eq!(
+                        unpacked, data,
+                        "{packing:?} with {bits} bits and {len} entries"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn entries_are_laid_out_like_the_game() {
+        assert_eq!(pack_data(&[1, 2, 3], 4, Packing::Padded), vec![0x321]);
+        // 12 entries of 5 bits fill a padded long, the 13th starts the next one.
+        let data = vec![31; 13];
+        assert_eq!(
+            pack_data(&data, 5, Packing::Padded),
+            vec![(1 << 60) - 1, 31]
+        );
+        // Spanning, the 13th entry takes the last 4 bits of the first long and 1 of the second.
+        assert_eq!(pack_data(&data, 5, Packing::Spanning), vec![-1, 1]);
+    }
+
+    #[test]
+    fn unpacks_exactly_len_entries() {
+        // 4096 entries of 5 bits leave 4 unused slots in the last padded long.
+        let data = random_entries(4096, 5, 1);
+        let mut packed = pack_data(&data, 5, Packing::Padded);
+        *packed.last_mut().unwrap() |= -1 << 20;
+        let unpacked = unpack_data(&packed, 5, 4096, Packing::Padded).unwrap();
+        assert_eq!(unpacked.len(), 4096);
+        assert_eq!(unpacked, data);
+    }
+
+    #[test]
+    fn direct_palettes_grow_past_8_bits() {
+        assert_eq!(bits_per_entry(256, 4), 8);
+        assert_eq!(bits_per_entry(257, 4), 9);
+        assert_eq!(bits_per_entry(4096, 4), 12);
+        let data = (0..4096)
+            .map(|i| (i * 37 % 4096) as u16)
+            .collect::<Vec<_>>();
+        for packing in PACKINGS {
+            let packed = pack_data(&data, 12, packing);
+            assert_eq!(unpack_data(&packed, 12, 4096, packing).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_long_count() {
+        for packing in PACKINGS {
+            let packed = pack_data(&random_entries(4096, 7, 3), 7, packing);
+            assert!(unpack_data(&packed[1..], 7, 4096, packing).is_err());
+            let mut longer = packed.clone();
+            longer.push(0);
+            assert!(unpack_data(&longer, 7, 4096, packing).is_err());
+            assert!(unpack_data(&packed, 7, 4000, packing).is_err());
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    io::{Read, Seek, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, ensure};
+use byteorder::{BigEndian, WriteBytesExt};
+use clap::ValueEnum;
+use fastanvil::{CompressionScheme, Region};
+use flate2::{
+    Compression,
+    write::{GzEncoder, ZlibEncoder},
+};
+use lz4_java_wrc::Lz4BlockOutput;
+use xxhash_rust::xxh64::xxh64;
+
+/// File format of the written region files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RegionFormat {
+    /// Minecraft's `.mca` files.
+    Anvil,
+    /// Linear v1 `.linear` files, one zstd stream per region, used by some server forks.
+    Linear,
+}
+
+impl RegionFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RegionFormat::Anvil => "mca",
+            RegionFormat::Linear => "linear",
+        }
+    }
+}
+
+/// Compression of each chunk stored in a region file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChunkCompression {
+    Zlib,
+    Gzip,
+    /// Uncompressed chunks, readable since Minecraft 1.15.1.
+    None,
+    /// LZ4 block stream chunks, readable since Minecraft 1.20.5.
+    Lz4,
+}
+
+/// zlib, gzip and zstd level used when none is given, fast with a fair ratio.
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 1;
+
+/// Block size `Lz4BlockOutput::new` compresses with.
+const LZ4_BLOCK_SIZE: u32 = 1 << 16;
+
+impl ChunkCompression {
+    /// Compresses serialized chunk NBT. `level` (0-9) applies to zlib and gzip only, the
+    /// conversion refuses a level for the other methods.
+    pub fn compress(&self, chunk: &[u8], level: u32) -> Result<(CompressionScheme, Vec<u8>)> {
+        ensure!(level <= 9, "Compression level {level} is not in 0-9");
+        Ok(match self {
+            ChunkCompression::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(chunk)?;
+                (CompressionScheme::Zlib, encoder.finish()?)
+            }
+            ChunkCompression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(chunk)?;
+                (CompressionScheme::Gzip, encoder.finish()?)
+            }
+            ChunkCompression::None => (CompressionScheme::Uncompressed, chunk.to_vec()),
+            ChunkCompression::Lz4 => {
+                let mut compressed = Vec::new();
+                {
+                    let mut encoder = Lz4BlockOutput::new(&mut compressed);
+                    encoder.write_all(chunk)?;
+                    encoder.flush()?;
+                }
+                // The empty block Java's `LZ4BlockOutputStream.finish` ends the stream with.
+                let compression_level = (32 - (LZ4_BLOCK_SIZE - 1).leading_zeros() - 10) as u8;
+                compressed.extend_from_slice(b"LZ4Block");
+                compressed.push(0x10 | compression_level);
+                compressed.extend_from_slice(&[0; 12]);
+                (CompressionScheme::Lz4, compressed)
+            }
+        })
+    }
+}
+
+pub enum RegionWriter<S: Read + Write + Seek> {
+    Anvil {
+        region: Region<S>,
+        compression: ChunkCompression,
+        compression_level: u32,
+    },
+    Linear(LinearRegion<S>),
+}
+
+impl<S: Read + Write + Seek> RegionWriter<S> {
+    /// `compression` only applies to Anvil, `compression_level` is the zstd level for Linear.
+    pub fn create(
+        stream: S,
+        format: RegionFormat,
+        compression: ChunkCompression,
+        compression_level: u32,
+    ) -> Result<Self> {
+        Ok(match format {
+            RegionFormat::Anvil => RegionWriter::Anvil {
+                region: Region::create(stream)?,
+                compression,
+                compression_level,
+            },
+            RegionFormat::Linear => {
+                RegionWriter::Linear(LinearRegion::new(stream, compression_level))
+            }
+        })
+    }
+
+    /// Writes serialized chunk NBT at region-local chunk position `x`, `z`.
+    pub fn write_chunk(&mut self, x: usize, z: usize, chunk: &[u8]) -> Result<()> {
+        match self {
+            RegionWriter::Anvil {
+                region,
+                compression,
+                compression_level,
+            } => {
+                let (scheme, chunk) = compression.compress(chunk, *compression_level)?;
+                region.write_compressed_chunk(x, z, scheme, &chunk)?;
+            }
+            RegionWriter::Linear(region) => region.write_chunk(x, z, chunk)?,
+        }
+        Ok(())
+    }
+
+    /// Writes out anything still buffered. Linear files are only written here.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            RegionWriter::Anvil { region, .. } => {
+                region.into_inner()?;
+            }
+            RegionWriter::Linear(region) => region.finish()?,
+        }
+        Ok(())
+    }
+}
+
+const LINEAR_SUPERBLOCK: i64 = -4323716122432332390;
+const LINEAR_VERSION: i8 = 1;
+
+/// A Linear v1 region, kept in memory until `finish`.
+///
+/// The file is a 32 byte header, a zstd frame and the superblock again as footer.
+/// The zstd frame holds the size and timestamp of all 1024 chunks, then their NBT back to back.
+/// The last header field is reserved for a data hash and holds the xxHash64 of the zstd frame.
+pub struct LinearRegion<W: Write> {
+    stream: W,
+    compression_level: u32,
+    // Serialized NBT and write timestamp, indexed by `z << 5 | x`.
+    chunks: Vec<Option<(Vec<u8>, u32)>>,
+}
+
+impl<W: Write> LinearRegion<W> {
+    pub fn new(stream: W, compression_level: u32) -> Self {
+        LinearRegion {
+            stream,
+            compression_level,
+            chunks: vec![None; 32 * 32],
+        }
+    }
+
+    pub fn write_chunk(&mut self, x: usize, z: usize, chunk: &[u8]) -> Result<()> {
+        ensure!(
+            x < 32 && z < 32,
+            "Chunk x:{x} z:{z} is outside of the region"
+        );
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+        self.chunks[z << 5 | x] = Some((chunk.to_vec(), timestamp));
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        let mut body = Vec::new();
+        for chunk in &self.chunks {
+            let (size, timestamp) = chunk.as_ref().map_or((0, 0), |(chunk, timestamp)| {
+                (chunk.len() as i32, *timestamp)
+            });
+            body.write_i32::<BigEndian>(size)?;
+            body.write_i32::<BigEndian>(timestamp as i32)?;
+        }
+        for (chunk, _) in self.chunks.iter().flatten() {
+            body.extend_from_slice(chunk);
+        }
+        let compressed = zstd::encode_all(body.as_slice(), self.compression_level as i32)?;
+        let newest_timestamp = self.chunks.iter().flatten().map(|(_, t)| *t).max();
+
+        self.stream.write_i64::<BigEndian>(LINEAR_SUPERBLOCK)?;
+        self.stream.write_i8(LINEAR_VERSION)?;
+        self.stream
+            .write_i64::<BigEndian>(newest_timestamp.unwrap_or(0) as i64)?;
+        self.stream.write_i8(self.compression_level as i8)?;
+        self.stream
+            .write_i16::<BigEndian>(self.chunks.iter().flatten().count() as i16)?;
+        self.stream
+            .write_i32::<BigEndian>(compressed.len() as i32)?;
+        self.stream
+            .write_i64::<BigEndian>(xxh64(&compressed, 0) as i64)?;
+        self.stream.write_all(&compressed)?;
+        self.stream.write_i64::<BigEndian>(LINEAR_SUPERBLOCK)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
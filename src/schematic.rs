@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, ensure};
+use fastnbt::{ByteArray, IntArray, LongArray, Value};
+use flate2::{Compression, write::GzEncoder};
+use serde::Serialize;
+
+use crate::{
+    chunk::BlockState,
+    packing::{Packing, bits_per_entry, pack_data},
+    volume::{BlockBox, BlockVolume},
+};
+
+const SPONGE_VERSION: i32 = 3;
+const LITEMATIC_VERSION: i32 = 6;
+const LITEMATIC_SUB_VERSION: i32 = 1;
+
+/// Root of a Sponge schematic file.
+#[derive(Debug, Serialize)]
+struct SpongeFile {
+    #[serde(rename = "Schematic")]
+    schematic: SpongeSchematic,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SpongeSchematic {
+    version: i32,
+    data_version: i32,
+    // Unsigned shorts.
+    width: i16,
+    height: i16,
+    length: i16,
+    /// World position of the minimum corner.
+    offset: IntArray,
+    blocks: SpongePalette,
+    biomes: SpongePalette,
+}
+
+/// Palette and varint encoded indices, one per block in `x + z * width + y * width * length`
+/// order.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SpongePalette {
+    palette: HashMap<String, i32>,
+    data: ByteArray,
+}
+
+impl SpongePalette {
+    fn new(palette: impl Iterator<Item = String>, data: &[u16]) -> Self {
+        let mut varints = Vec::with_capacity(data.len());
+        for id in data {
+            let mut id = *id as u32;
+            while id >= 0x80 {
+                varints.push((id as u8 & 0x7f | 0x80) as i8);
+                id >>= 7;
+            }
+            varints.push(id as i8);
+        }
+        SpongePalette {
+            palette: palette.zip(0..).collect(),
+            data: ByteArray::new(varints),
+        }
+    }
+}
+
+/// Fails if a Sponge schematic cannot hold `bounds`, before its blocks are read.
+pub fn check_sponge_bounds(bounds: &BlockBox) -> Result<()> {
+    bounds.check_size()?;
+    for (axis, size) in [
+        ("width", bounds.width()),
+        ("height", bounds.height()),
+        ("length", bounds.length()),
+    ] {
+        ensure!(
+            size <= u16::MAX as usize,
+            "Schematic {axis} {size} is larger than {}",
+            u16::MAX
+        );
+    }
+    Ok(())
+}
+
+/// Writes `volume` as a gzipped Sponge schematic v3.
+pub fn write_sponge_schematic(
+    volume: &BlockVolume,
+    data_version: i32,
+    file: impl AsRef<Path>,
+) -> Result<()> {
+    let bounds = &volume.bounds;
+    check_sponge_bounds(bounds)?;
+    // `BlockVolume` uses the same block order as Sponge schematics.
+    let schematic = SpongeFile {
+        schematic: SpongeSchematic {
+            version: SPONGE_VERSION,
+            data_version,
+            width: bounds.width() as u16 as i16,
+            height: bounds.height() as u16 as i16,
+            length: bounds.length() as u16 as i16,
+            offset: IntArray::new(vec![bounds.min.x, bounds.min.y, bounds.min.z]),
+            blocks: SpongePalette::new(
+                volume.block_palette.iter().map(|block| block.to_string()),
+                &volume.blocks,
+            ),
+            biomes: SpongePalette::new(volume.biome_palette.iter().cloned(), &volume.biomes),
+        },
+    };
+    let file = BufWriter::new(File::create(file)?);
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    fastnbt::to_writer(&mut encoder, &schematic)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Names shown by Litematica for a schematic.
+#[derive(Debug, Clone)]
+pub struct LitematicMetadata {
+    pub name: String,
+    pub author: String,
+    pub description: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LitematicFile {
+    minecraft_data_version: i32,
+    version: i32,
+    sub_version: i32,
+    metadata: LitematicFileMetadata,
+    regions: HashMap<String, LitematicRegion>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LitematicFileMetadata {
+    name: String,
+    author: String,
+    description: String,
+    region_count: i32,
+    total_volume: i32,
+    /// Number of blocks that are not air.
+    total_blocks: i32,
+    /// Milliseconds since the Unix epoch.
+    time_created: i64,
+    time_modified: i64,
+    enclosing_size: LitematicVec,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct LitematicVec {
+    x: i32,
+    y: i32,
+    z: i32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct LitematicRegion {
+    /// Position relative to the schematic origin.
+    position: LitematicVec,
+    size: LitematicVec,
+    block_state_palette: Vec<BlockState>,
+    /// Palette indices in the same order as `BlockVolume`, packed across longs.
+    block_states: LongArray,
+    tile_entities: Vec<Value>,
+    entities: Vec<Value>,
+    pending_block_ticks: Vec<Value>,
+    pending_fluid_ticks: Vec<Value>,
+}
+
+/// Fails if a Litematica schematic cannot hold `bounds`, before its blocks are read.
+pub fn check_litematic_bounds(bounds: &BlockBox) -> Result<()> {
+    bounds.check_size()?;
+    let blocks = bounds.width() * bounds.height() * bounds.length();
+    ensure!(
+        blocks <= i32::MAX as usize,
+        "Litematic volume {blocks} is larger than {}",
+        i32::MAX
+    );
+    Ok(())
+}
+
+/// Writes `volume` as a gzipped Litematica schematic with one region named after the schematic.
+pub fn write_litematic(
+    volume: &BlockVolume,
+    metadata: &LitematicMetadata,
+    data_version: i32,
+    file: impl AsRef<Path>,
+) -> Result<()> {
+    let bounds = &volume.bounds;
+    check_litematic_bounds(bounds)?;
+    let size = LitematicVec {
+        x: bounds.width() as i32,
+        y: bounds.height() as i32,
+        z: bounds.length() as i32,
+    };
+    let bits = bits_per_entry(volume.block_palette.len(), 2);
+    let region = LitematicRegion {
+        position: LitematicVec { x: 0, y: 0, z: 0 },
+        size,
+        block_state_palette: volume.block_palette.clone(),
+        block_states: LongArray::new(pack_data(&volume.blocks, bits, Packing::Spanning)),
+        tile_entities: Vec::new(),
+        entities: Vec::new(),
+        pending_block_ticks: Vec::new(),
+        pending_fluid_ticks: Vec::new(),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    let litematic = LitematicFile {
+        minecraft_data_version: data_version,
+        version: LITEMATIC_VERSION,
+        sub_version: LITEMATIC_SUB_VERSION,
+        metadata: LitematicFileMetadata {
+            name: metadata.name.clone(),
+            author: metadata.author.clone(),
+            description: metadata.description.clone(),
+            region_count: 1,
+            total_volume: volume.blocks.len() as i32,
+            total_blocks: volume.blocks.iter().filter(|block| **block != 0).count() as i32,
+            time_created: now,
+            time_modified: now,
+            enclosing_size: size,
+        },
+        regions: HashMap::from([(metadata.name.clone(), region)]),
+    };
+    let file = BufWriter::new(File::create(file)?);
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    fastnbt::to_writer(&mut encoder, &litematic)?;
+    encoder.finish()?;
+    Ok(())
+}
@@ -1,28 +1,391 @@
-use std::{collections::HashMap, path::Path, sync::Mutex};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{Result, bail};
-use rusqlite::Connection;
+use anyhow::{Result, anyhow, bail, ensure};
+use clap::ValueEnum;
+use rusqlite::{Connection, ErrorCode, MAIN_DB, OpenFlags, params, params_from_iter, types::Value};
+use tempfile::NamedTempFile;
 
 use crate::{
     data::{
-        DHDataRequester, DHSectionData, DHSectionPos, RegionPos, deserialize_data,
-        deserialize_mapping,
+        DH_DATA_FORMAT_VERSION, DHDataRequester, DHSectionData, DHSectionPos, RegionPos,
+        deserialize_data, deserialize_mapping, serialize_data, serialize_mapping,
     },
     decompress::CompressionMode,
 };
 
-pub struct DHDBConn(pub Connection);
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// `ColumnGenerationStep` of a column with data, the last world generation step (light).
+const GENERATION_STEP_LIGHT: u8 = 9;
+/// `ColumnGenerationStep` of a column without data.
+const GENERATION_STEP_EMPTY: u8 = 0;
+/// `ColumnWorldCompressionMode` that merges the same blocks, what DH writes by default.
+const WORLD_COMPRESSION_MERGE_SAME_BLOCKS: u8 = 0;
+/// `DataFormatVersion` of sections in databases without that column.
+const DEFAULT_DATA_FORMAT_VERSION: &str = "1";
+/// Columns of the `FullData` table this tool writes, in the order of `CREATE_FULL_DATA_TABLE`.
+const FULL_DATA_COLUMNS: [&str; 14] = [
+    "DetailLevel",
+    "PosX",
+    "PosZ",
+    "MinY",
+    "DataChecksum",
+    "Data",
+    "ColumnGenerationStep",
+    "ColumnWorldCompressionMode",
+    "Mapping",
+    "DataFormatVersion",
+    "CompressionMode",
+    "ApplyToParent",
+    "LastModifiedUnixDateTime",
+    "CreatedUnixDateTime",
+];
+const CREATE_FULL_DATA_TABLE: &str = "CREATE TABLE IF NOT EXISTS FullData(DetailLevel INTEGER, PosX INTEGER, PosZ INTEGER, MinY INTEGER, DataChecksum INTEGER, Data BLOB, ColumnGenerationStep BLOB, ColumnWorldCompressionMode BLOB, Mapping BLOB, DataFormatVersion INTEGER, CompressionMode INTEGER, ApplyToParent INTEGER, LastModifiedUnixDateTime INTEGER, CreatedUnixDateTime INTEGER, PRIMARY KEY (DetailLevel, PosX, PosZ));";
+
+/// How the DH database file is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DBOpenMode {
+    /// Open read-only. Works while the game is running, but waits on its locks.
+    ReadOnly,
+    /// Open as immutable, ignoring locks and the WAL file.
+    /// Changes not yet checkpointed by a running game are not visible.
+    Immutable,
+    /// Copy the database into a temporary file with SQLite's backup API and read the copy.
+    Snapshot,
+    /// Open an existing database for writing, failing if another process holds a lock on it.
+    #[value(skip)]
+    ReadWrite,
+}
+
+/// Columns a `FullData` table must have to be read.
+const REQUIRED_COLUMNS: [&str; 7] = [
+    "DetailLevel",
+    "PosX",
+    "PosZ",
+    "MinY",
+    "Data",
+    "Mapping",
+    "CompressionMode",
+];
+
+/// Table in which DH records the migration scripts it applied to the database, by name.
+const MIGRATION_TABLE: &str = "Schema";
+/// DH migration scripts whose changes to the `FullData` table this tool knows.
+const KNOWN_MIGRATIONS: [&str; 7] = [
+    "0010-sqlite-createInitialDataTables.sql",
+    "0020-sqlite-createFullDataSourceV2Tables.sql",
+    "0030-sqlite-changeTableJournaling.sql",
+    "0040-sqlite-removeRenderCache.sql",
+    "0050-sqlite-addApplyToParentIndex.sql",
+    "0060-sqlite-createChunkHashTable.sql",
+    "0070-sqlite-createBeaconBeamTable.sql",
+];
+
+/// Layout of the DH database, told apart by the key of its `FullData` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DHSchemaVersion {
+    /// `FullData` keyed by a `DhSectionPos` string, written by older DH releases.
+    /// Its data format differs and is not supported.
+    SectionPosKeyed,
+    /// `FullData` keyed by `DetailLevel`, `PosX` and `PosZ`.
+    PositionKeyed,
+}
+
+/// Schema of the `FullData` table of an opened database.
+#[derive(Debug, Clone)]
+struct DHSchema {
+    columns: Vec<String>,
+}
+
+impl DHSchema {
+    /// Reads the schema of `conn`, failing if it is not a DH database this tool can read.
+    fn read(conn: &Connection, file: &Path) -> Result<DHSchema> {
+        let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('FullData')")?;
+        let columns = stmt
+            .query_map([], |row| row.get(0))
+            .and_then(|rows| rows.collect::<Result<Vec<String>, _>>())
+            .map_err(|e| explain_sql_error(e, file))?;
+        ensure!(
+            !columns.is_empty(),
+            "'{}' has no FullData table, it is not a Distant Horizons database",
+            file.display()
+        );
+        let has = |column: &str| columns.iter().any(|c| c == column);
+        let version = if has("DhSectionPos") {
+            DHSchemaVersion::SectionPosKeyed
+        } else {
+            DHSchemaVersion::PositionKeyed
+        };
+        if let DHSchemaVersion::SectionPosKeyed = version {
+            bail!(
+                "'{}' was written by an older Distant Horizons release, its FullData table is keyed by DhSectionPos, which is not supported. Load the world once with a current Distant Horizons release to migrate it.",
+                file.display()
+            );
+        }
+        let missing = REQUIRED_COLUMNS
+            .into_iter()
+            .filter(|column| !has(column))
+            .collect::<Vec<_>>();
+        ensure!(
+            missing.is_empty(),
+            "The FullData table of '{}' lacks the columns {}, this Distant Horizons database version is not supported",
+            file.display(),
+            missing.join(", ")
+        );
+        check_migrations(conn, file, &columns)?;
+        Ok(DHSchema { columns })
+    }
+
+    fn has(&self, column: &str) -> bool {
+        self.columns.iter().any(|c| c == column)
+    }
+
+    /// `column` in a query, or `default` for databases without it.
+    fn column_or<'a>(&self, column: &'a str, default: &'a str) -> &'a str {
+        if self.has(column) { column } else { default }
+    }
+}
+
+/// Fails if DH applied migration scripts to the database that this tool does not know and
+/// that changed the `FullData` table, which shows as `columns` this tool does not know.
+/// Unknown scripts that leave the table alone, like ones adding other tables, are accepted.
+/// Databases without the migration table, like the ones this tool writes, are not checked.
+fn check_migrations(conn: &Connection, file: &Path, columns: &[String]) -> Result<()> {
+    let has_table: bool = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+            [MIGRATION_TABLE],
+            |row| row.get(0),
+        )
+        .map_err(|e| explain_sql_error(e, file))?;
+    if !has_table {
+        return Ok(());
+    }
+    let unknown_migrations = conn
+        .prepare(&format!("SELECT ScriptName FROM {MIGRATION_TABLE}"))
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map(|scripts| {
+            scripts
+                .into_iter()
+                .filter(|script| !KNOWN_MIGRATIONS.contains(&script.as_str()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_else(|_| vec![format!("an unreadable {MIGRATION_TABLE} table")]);
+    if unknown_migrations.is_empty() {
+        return Ok(());
+    }
+    let unknown_columns = columns
+        .iter()
+        .map(String::as_str)
+        .filter(|column| !FULL_DATA_COLUMNS.contains(column))
+        .collect::<Vec<_>>();
+    ensure!(
+        unknown_columns.is_empty(),
+        "'{}' was migrated by a Distant Horizons release this tool does not know ({}), which added the FullData columns {}. This database version is not supported yet.",
+        file.display(),
+        unknown_migrations.join(", "),
+        unknown_columns.join(", ")
+    );
+    Ok(())
+}
+
+pub struct DHDBConn {
+    conn: Connection,
+    schema: DHSchema,
+    // Dropped after `conn`, so the snapshot file is closed before it is removed.
+    _snapshot: Option<NamedTempFile>,
+}
 
 impl DHDBConn {
-    pub fn get_conn(file: impl AsRef<Path>) -> Result<DHDBConn> {
-        Ok(DHDBConn(Connection::open(file)?))
+    pub fn get_conn(file: impl AsRef<Path>, mode: DBOpenMode) -> Result<DHDBConn> {
+        let file = file.as_ref();
+        let (conn, snapshot) = match mode {
+            DBOpenMode::ReadOnly => (open_read_only(file)?, None),
+            DBOpenMode::Immutable => (open_immutable(file)?, None),
+            DBOpenMode::Snapshot => {
+                let snapshot = NamedTempFile::with_prefix("dh2anvil-snapshot-")?;
+                open_read_only(file)?
+                    .backup(MAIN_DB, snapshot.path(), None)
+                    .map_err(|e| explain_sql_error(e, file))?;
+                (open_read_only(snapshot.path())?, Some(snapshot))
+            }
+            DBOpenMode::ReadWrite => (open_read_write(file)?, None),
+        };
+        // Reading the schema also reports a locked database here instead of mid-conversion.
+        Ok(DHDBConn {
+            schema: DHSchema::read(&conn, file)?,
+            conn,
+            _snapshot: snapshot,
+        })
+    }
+
+    /// Opens `file` for writing, creating it and its `FullData` table if they do not exist.
+    pub fn create(file: impl AsRef<Path>) -> Result<DHDBConn> {
+        let file = file.as_ref();
+        let conn = Connection::open(file).map_err(|e| explain_sql_error(e, file))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute(CREATE_FULL_DATA_TABLE, [])
+            .map_err(|e| explain_sql_error(e, file))?;
+        Ok(DHDBConn {
+            schema: DHSchema::read(&conn, file)?,
+            conn,
+            _snapshot: None,
+        })
+    }
+
+    /// Copies the whole database into a new file at `file`, which must not exist.
+    pub fn backup_to(&self, file: impl AsRef<Path>) -> Result<()> {
+        let file = file.as_ref();
+        ensure!(!file.exists(), "'{}' already exists", file.display());
+        self.conn
+            .backup(MAIN_DB, file, None)
+            .map_err(|e| explain_sql_error(e, file))?;
+        Ok(())
+    }
+
+    /// Detail level, position and `LastModifiedUnixDateTime` of every stored section.
+    pub fn get_section_keys(&self) -> Result<Vec<(u8, DHSectionPos, Option<i64>)>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT DetailLevel, PosX, PosZ, {} FROM FullData",
+            self.schema.column_or("LastModifiedUnixDateTime", "NULL")
+        ))?;
+        let keys_iter = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                DHSectionPos {
+                    x: row.get(1)?,
+                    z: row.get(2)?,
+                },
+                row.get(3)?,
+            ))
+        })?;
+        let mut keys = Vec::new();
+        for key in keys_iter {
+            keys.push(key?);
+        }
+        Ok(keys)
+    }
+
+    /// Deletes the sections of every detail level above `detail_level`.
+    pub fn delete_detail_levels_above(&self, detail_level: u8) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM FullData WHERE DetailLevel > ?", [detail_level])?;
+        Ok(())
+    }
+
+    /// Copies the `FullData` rows of `source` at `keys` as they are, replacing the rows stored
+    /// at the same detail level and position. Columns that only one side has are left out.
+    pub fn copy_rows(&self, source: &DHDBConn, keys: &[(u8, DHSectionPos)]) -> Result<()> {
+        let columns = self
+            .schema
+            .columns
+            .iter()
+            .map(String::as_str)
+            .filter(|column| source.schema.has(column))
+            .collect::<Vec<_>>();
+        let mut select = source.conn.prepare(&format!(
+            "SELECT {} FROM FullData WHERE DetailLevel = ? and PosX = ? and PosZ = ?;",
+            columns.join(", ")
+        ))?;
+        let transaction = self.conn.unchecked_transaction()?;
+        {
+            let mut insert = transaction.prepare(&format!(
+                "INSERT OR REPLACE INTO FullData ({}) VALUES ({});",
+                columns.join(", "),
+                vec!["?"; columns.len()].join(", ")
+            ))?;
+            for (detail_level, pos) in keys {
+                let values = select.query_row(params![detail_level, pos.x, pos.z], |row| {
+                    (0..columns.len())
+                        .map(|i| row.get::<_, Value>(i))
+                        .collect::<Result<Vec<_>, _>>()
+                })?;
+                insert.execute(params_from_iter(values))?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Writes `sections` at `detail_level` in one transaction, replacing the sections already
+    /// stored at their positions. They are marked to be applied to their parent detail level.
+    /// Columns the database does not have are left out.
+    pub fn put_sections(&self, detail_level: u8, sections: &[DHSectionData]) -> Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        {
+            let columns = FULL_DATA_COLUMNS
+                .into_iter()
+                .filter(|column| self.schema.has(column))
+                .collect::<Vec<_>>();
+            let mut stmt = transaction.prepare_cached(&format!(
+                "INSERT OR REPLACE INTO FullData ({}) VALUES ({});",
+                columns.join(", "),
+                vec!["?"; columns.len()].join(", ")
+            ))?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+            for section in sections {
+                let compression_mode = &section.compression_mode;
+                let generation_steps = section
+                    .data
+                    .iter()
+                    .map(|data_col| {
+                        if data_col.is_empty() {
+                            GENERATION_STEP_EMPTY
+                        } else {
+                            GENERATION_STEP_LIGHT
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let world_compression_modes =
+                    vec![WORLD_COMPRESSION_MERGE_SAME_BLOCKS; section.data.len()];
+                // In the order of `FULL_DATA_COLUMNS`.
+                let values: [Value; 14] = [
+                    detail_level.into(),
+                    section.pos.x.into(),
+                    section.pos.z.into(),
+                    section.min_y.into(),
+                    0.into(),
+                    serialize_data(&section.data, compression_mode)?.into(),
+                    compression_mode.compress(generation_steps)?.into(),
+                    compression_mode.compress(world_compression_modes)?.into(),
+                    serialize_mapping(&section.mapping, compression_mode)?.into(),
+                    section.data_format_version.into(),
+                    (*compression_mode as i32).into(),
+                    1.into(),
+                    now.into(),
+                    now.into(),
+                ];
+                stmt.execute(params_from_iter(
+                    FULL_DATA_COLUMNS
+                        .iter()
+                        .zip(values)
+                        .filter(|(column, _)| self.schema.has(column))
+                        .map(|(_, value)| value),
+                ))?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
     }
 
     pub fn get_section_poses(&self) -> Result<Vec<DHSectionPos>> {
+        self.get_detail_level_poses(0)
+    }
+
+    /// Positions of the sections stored at `detail_level`.
+    pub fn get_detail_level_poses(&self, detail_level: u8) -> Result<Vec<DHSectionPos>> {
         let mut stmt = self
-            .0
-            .prepare_cached("SELECT PosX, PosZ FROM FullData WHERE DetailLevel = 0")?;
-        let poses_iter = stmt.query_map([], |row| {
+            .conn
+            .prepare_cached("SELECT PosX, PosZ FROM FullData WHERE DetailLevel = ?")?;
+        let poses_iter = stmt.query_map([detail_level], |row| {
             Ok(DHSectionPos {
                 x: row.get(0)?,
                 z: row.get(1)?,
@@ -39,13 +402,28 @@ impl DHDBConn {
         &self,
         region_pos: &RegionPos,
     ) -> Result<HashMap<DHSectionPos, DHSectionData>> {
-        let mut stmt = self.0.prepare_cached(
-            "SELECT PosX, PosZ, MinY, Data, Mapping, DataFormatVersion, CompressionMode FROM FullData WHERE DetailLevel = 0 and $pos_x_min <= PosX and PosX < $pos_x_max and $pos_z_min <= PosZ and PosZ < $pos_z_max;"
-        )?;
         let (section_min_x, section_min_z) = (region_pos.x << 3, region_pos.z << 3);
-        let (section_max_x, section_max_z) = (section_min_x + 8, section_min_z + 8);
+        self.get_sections_in_area(
+            0,
+            section_min_x..section_min_x + 8,
+            section_min_z..section_min_z + 8,
+        )
+    }
+
+    /// Sections stored at `detail_level` with their position in `xs` and `zs`.
+    pub fn get_sections_in_area(
+        &self,
+        detail_level: u8,
+        xs: Range<i32>,
+        zs: Range<i32>,
+    ) -> Result<HashMap<DHSectionPos, DHSectionData>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT PosX, PosZ, MinY, Data, Mapping, {}, CompressionMode, {} FROM FullData WHERE DetailLevel = $detail_level and $pos_x_min <= PosX and PosX < $pos_x_max and $pos_z_min <= PosZ and PosZ < $pos_z_max;",
+            self.schema.column_or("DataFormatVersion", DEFAULT_DATA_FORMAT_VERSION),
+            self.schema.column_or("LastModifiedUnixDateTime", "NULL")
+        ))?;
         let raw_sections_iter = stmt.query_map(
-            [section_min_x, section_max_x, section_min_z, section_max_z],
+            params![detail_level, xs.start, xs.end, zs.start, zs.end],
             |row| {
                 Ok((
                     row.get(0)?,
@@ -55,13 +433,26 @@ impl DHDBConn {
                     row.get(4)?,
                     row.get(5)?,
                     row.get(6)?,
+                    row.get(7)?,
                 ))
             },
         )?;
         let mut sections = HashMap::new();
         for raw_section in raw_sections_iter {
-            let (pos_x, pos_z, min_y, data, mapping, data_format_version, compression_mode_num) =
-                raw_section?;
+            let (
+                pos_x,
+                pos_z,
+                min_y,
+                data,
+                mapping,
+                data_format_version,
+                compression_mode_num,
+                last_modified,
+            ) = raw_section?;
+            ensure!(
+                data_format_version == DH_DATA_FORMAT_VERSION,
+                "Section {pos_x}, {pos_z} at detail level {detail_level} has the data format version {data_format_version}, only version {DH_DATA_FORMAT_VERSION} is supported"
+            );
             let compression_mode = CompressionMode::from_num(compression_mode_num);
             let compression_mode = if let Some(compression_mode) = compression_mode {
                 compression_mode
@@ -77,6 +468,7 @@ impl DHDBConn {
                     mapping: deserialize_mapping(mapping, &compression_mode)?,
                     data_format_version,
                     compression_mode,
+                    last_modified,
                 },
             );
         }
@@ -84,6 +476,67 @@ impl DHDBConn {
     }
 }
 
+fn open_read_only(file: &Path) -> Result<Connection> {
+    let conn = Connection::open_with_flags(
+        file,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| explain_sql_error(e, file))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+fn open_read_write(file: &Path) -> Result<Connection> {
+    let conn = Connection::open_with_flags(
+        file,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| explain_sql_error(e, file))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // Taking the write lock once fails now if the game still holds it, not after hours of work.
+    conn.execute_batch("BEGIN IMMEDIATE; ROLLBACK;")
+        .map_err(|e| match e.sqlite_error_code() {
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => anyhow!(
+                "DH Lod data file '{}' is locked, the game or server may still be using it. Close it first, or write into a copy with `--out`.",
+                file.display()
+            ),
+            _ => anyhow!(e),
+        })?;
+    Ok(conn)
+}
+
+fn open_immutable(file: &Path) -> Result<Connection> {
+    let path = file
+        .to_str()
+        .ok_or_else(|| {
+            anyhow!(
+                "DH Lod data file path '{}' is not valid UTF-8",
+                file.display()
+            )
+        })?
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    let conn = Connection::open_with_flags(
+        format!("file:{path}?immutable=1"),
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| explain_sql_error(e, file))?;
+    Ok(conn)
+}
+
+fn explain_sql_error(err: rusqlite::Error, file: &Path) -> anyhow::Error {
+    match err.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => anyhow!(
+            "DH Lod data file '{}' is locked, the game or server may still be writing to it. Close it first, or retry with `--db-mode snapshot` or `--db-mode immutable`.",
+            file.display()
+        ),
+        _ => anyhow!(err),
+    }
+}
+
 impl DHDataRequester for Mutex<DHDBConn> {
     fn get_section_poses(&self) -> Result<Vec<DHSectionPos>> {
         self.lock()
@@ -100,3 +553,50 @@ impl DHDataRequester for Mutex<DHDBConn> {
             .get_sections_in_region(pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Columns of a `FullData` table as DH creates it, and `extra` ones.
+    fn columns(extra: &[&str]) -> Vec<String> {
+        FULL_DATA_COLUMNS
+            .iter()
+            .chain(extra)
+            .map(|column| column.to_string())
+            .collect()
+    }
+
+    fn migrated(scripts: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE Schema(ScriptName TEXT PRIMARY KEY, AppliedDateTime DATETIME);",
+        )
+        .unwrap();
+        for script in scripts {
+            conn.execute("INSERT INTO Schema(ScriptName) VALUES (?)", [script])
+                .unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn only_unknown_migrations_changing_full_data_are_refused() {
+        let file = Path::new("test.sqlite");
+        let no_table = Connection::open_in_memory().unwrap();
+        assert!(check_migrations(&no_table, file, &columns(&["NewColumn"])).is_ok());
+
+        let known = migrated(&KNOWN_MIGRATIONS);
+        assert!(check_migrations(&known, file, &columns(&[])).is_ok());
+        assert!(check_migrations(&known, file, &columns(&["NewColumn"])).is_ok());
+
+        let unknown = migrated(&[KNOWN_MIGRATIONS[0], "0900-sqlite-createOtherTable.sql", "x"]);
+        assert!(check_migrations(&unknown, file, &columns(&[])).is_ok());
+        let err = check_migrations(&unknown, file, &columns(&["NewColumn"])).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("0900-sqlite-createOtherTable.sql, x")
+        );
+        assert!(err.to_string().contains("NewColumn"));
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::{
+    chunk::{AIR, BlockState, ChunkPalette},
+    data::{DH_SECTION_WIDTH, DHDataRequester, DHSectionPos, RegionPos},
+    worldgen::{ColumnTarget, WorldGenOptions, fill_dh_column, resolve_mapping},
+};
+
+const DEFAULT_BIOME: &str = "minecraft:plains";
+const UNSET_BIOME: u16 = u16::MAX;
+/// Most blocks a `BlockVolume` is read with. Each takes two `u16`, so this is 1 GiB.
+pub const MAX_VOLUME_BLOCKS: usize = 1 << 28;
+
+/// A block position in world coordinates, parsed from `x,y,z`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockPos {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl FromStr for BlockPos {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords = s
+            .split(',')
+            .map(|coord| {
+                coord
+                    .trim()
+                    .parse::<i32>()
+                    .with_context(|| format!("'{coord}' is not a block coordinate"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        match coords[..] {
+            [x, y, z] => Ok(BlockPos { x, y, z }),
+            _ => bail!("Expected a block position as `x,y,z`, found '{s}'"),
+        }
+    }
+}
+
+/// An axis aligned box of blocks, both corners included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockBox {
+    pub min: BlockPos,
+    pub max: BlockPos,
+}
+
+impl BlockBox {
+    /// The box spanned by two opposite corners in any order.
+    pub fn new(a: BlockPos, b: BlockPos) -> Self {
+        BlockBox {
+            min: BlockPos {
+                x: a.x.min(b.x),
+                y: a.y.min(b.y),
+                z: a.z.min(b.z),
+            },
+            max: BlockPos {
+                x: a.x.max(b.x),
+                y: a.y.max(b.y),
+                z: a.z.max(b.z),
+            },
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        (self.max.x as i64 - self.min.x as i64 + 1) as usize
+    }
+
+    pub fn height(&self) -> usize {
+        (self.max.y as i64 - self.min.y as i64 + 1) as usize
+    }
+
+    pub fn length(&self) -> usize {
+        (self.max.z as i64 - self.min.z as i64 + 1) as usize
+    }
+
+    /// Fails if the box holds more than [`MAX_VOLUME_BLOCKS`] blocks.
+    pub fn check_size(&self) -> Result<()> {
+        let blocks = self
+            .width()
+            .checked_mul(self.height())
+            .and_then(|blocks| blocks.checked_mul(self.length()));
+        ensure!(
+            blocks.is_some_and(|blocks| blocks <= MAX_VOLUME_BLOCKS),
+            "The box of {}x{}x{} blocks is larger than the {MAX_VOLUME_BLOCKS} blocks that can be exported at once",
+            self.width(),
+            self.height(),
+            self.length()
+        );
+        Ok(())
+    }
+
+    /// DH sections that hold columns of the box.
+    fn section_poses(&self) -> Vec<DHSectionPos> {
+        let width = DH_SECTION_WIDTH as i32;
+        let mut poses = Vec::new();
+        for x in self.min.x.div_euclid(width)..=self.max.x.div_euclid(width) {
+            for z in self.min.z.div_euclid(width)..=self.max.z.div_euclid(width) {
+                poses.push(DHSectionPos { x, z });
+            }
+        }
+        poses
+    }
+}
+
+/// Reconstructs the DH columns in `bounds` into `target`, like region files are.
+///
+/// Columns are passed to `target` relative to the box minimum and with their full height,
+/// the parts above and below the box are left to `target`.
+pub fn read_box(
+    section_requester: &impl DHDataRequester,
+    bounds: &BlockBox,
+    options: &WorldGenOptions,
+    target: &mut impl ColumnTarget,
+) -> Result<()> {
+    let section_poses = bounds.section_poses();
+    let region_poses = section_poses
+        .iter()
+        .map(|pos| RegionPos::from(*pos))
+        .collect::<HashSet<_>>();
+    for region_pos in region_poses {
+        let dh_sections = section_requester.request_sections_in_region(&region_pos)?;
+        for section_pos in &section_poses {
+            let Some(dh_section) = dh_sections.get(section_pos) else {
+                continue;
+            };
+            let (palette, mapping_ids) = resolve_mapping(dh_section, options);
+            for x in 0..DH_SECTION_WIDTH {
+                for z in 0..DH_SECTION_WIDTH {
+                    let block_x = section_pos.x * DH_SECTION_WIDTH as i32 + x as i32;
+                    let block_z = section_pos.z * DH_SECTION_WIDTH as i32 + z as i32;
+                    if !(bounds.min.x..=bounds.max.x).contains(&block_x)
+                        || !(bounds.min.z..=bounds.max.z).contains(&block_z)
+                    {
+                        continue;
+                    }
+                    fill_dh_column(
+                        target,
+                        (block_x - bounds.min.x) as u32,
+                        (block_z - bounds.min.z) as u32,
+                        &dh_section.data[x * DH_SECTION_WIDTH + z],
+                        &mapping_ids,
+                        &palette,
+                        options,
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Blocks and biomes of a `BlockBox`, reconstructed from DH data like region files are.
+///
+/// Blocks are indexed by `(y * length + z) * width + x` relative to the box minimum.
+#[derive(Debug)]
+pub struct BlockVolume {
+    pub bounds: BlockBox,
+    /// Index 0 is air.
+    pub block_palette: Vec<BlockState>,
+    pub blocks: Vec<u16>,
+    pub biome_palette: Vec<String>,
+    pub biomes: Vec<u16>,
+    block_ids: HashMap<BlockState, u16>,
+    biome_ids: HashMap<String, u16>,
+}
+
+impl BlockVolume {
+    /// A volume of air without biomes.
+    fn new(bounds: BlockBox) -> Self {
+        let air = BlockState {
+            name: AIR.to_string(),
+            properties: None,
+        };
+        let len = bounds.width() * bounds.height() * bounds.length();
+        BlockVolume {
+            bounds,
+            block_palette: vec![air.clone()],
+            blocks: vec![0; len],
+            biome_palette: Vec::new(),
+            biomes: vec![UNSET_BIOME; len],
+            block_ids: HashMap::from([(air, 0)]),
+            biome_ids: HashMap::new(),
+        }
+    }
+
+    /// Reconstructs the blocks in `bounds` from the DH sections of `section_requester`.
+    ///
+    /// Blocks with nothing stored stay air. Their biome is taken from the nearest block of the
+    /// same column that has one, or plains for columns without any data.
+    pub fn read(
+        section_requester: &impl DHDataRequester,
+        bounds: BlockBox,
+        options: &WorldGenOptions,
+    ) -> Result<Self> {
+        bounds.check_size()?;
+        let mut volume = BlockVolume::new(bounds);
+        read_box(section_requester, &bounds, options, &mut volume)?;
+        volume.fill_unset_biomes();
+        Ok(volume)
+    }
+
+    pub fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        (y * self.bounds.length() + z) * self.bounds.width() + x
+    }
+
+    fn block_id(&mut self, block: &BlockState) -> u16 {
+        if let Some(id) = self.block_ids.get(block) {
+            return *id;
+        }
+        let id = self.block_palette.len() as u16;
+        self.block_palette.push(block.clone());
+        self.block_ids.insert(block.clone(), id);
+        id
+    }
+
+    fn biome_id(&mut self, biome: &str) -> u16 {
+        if let Some(id) = self.biome_ids.get(biome) {
+            return *id;
+        }
+        let id = self.biome_palette.len() as u16;
+        self.biome_palette.push(biome.to_string());
+        self.biome_ids.insert(biome.to_string(), id);
+        id
+    }
+
+    /// Gives every block without a biome the biome of the nearest block below it, or above it
+    /// when there is none below.
+    fn fill_unset_biomes(&mut self) {
+        for x in 0..self.bounds.width() {
+            for z in 0..self.bounds.length() {
+                let column = (0..self.bounds.height())
+                    .map(|y| self.index(x, y, z))
+                    .collect::<Vec<_>>();
+                let mut biome = None;
+                for &i in &column {
+                    match self.biomes[i] {
+                        UNSET_BIOME => {
+                            if let Some(biome) = biome {
+                                self.biomes[i] = biome;
+                            }
+                        }
+                        set => biome = Some(set),
+                    }
+                }
+                let mut biome = match biome {
+                    Some(biome) => biome,
+                    None => self.biome_id(DEFAULT_BIOME),
+                };
+                for &i in column.iter().rev() {
+                    match self.biomes[i] {
+                        UNSET_BIOME => self.biomes[i] = biome,
+                        set => biome = set,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ColumnTarget for BlockVolume {
+    /// Fills the blocks at box-relative `x`, `z`, the parts of `ys` outside the box are ignored.
+    fn fill_column(
+        &mut self,
+        x: u32,
+        z: u32,
+        ys: Range<i32>,
+        block: u16,
+        biome: u16,
+        palette: &ChunkPalette,
+    ) -> Result<()> {
+        ensure!(
+            (x as usize) < self.bounds.width() && (z as usize) < self.bounds.length(),
+            "x or z is out of bounds x:{x} z:{z}"
+        );
+        let ys = ys.start.max(self.bounds.min.y)..ys.end.min(self.bounds.max.y + 1);
+        if ys.is_empty() {
+            return Ok(());
+        }
+        let block = self.block_id(palette.block(block));
+        let biome = self.biome_id(palette.biome(biome));
+        for y in ys {
+            let i = self.index(x as usize, (y - self.bounds.min.y) as usize, z as usize);
+            self.blocks[i] = block;
+            self.biomes[i] = biome;
+        }
+        Ok(())
+    }
+
+    /// Volumes have no scheduled ticks.
+    fn schedule_fluid_tick(&mut self, _x: u32, _y: i32, _z: u32, _fluid: &str) {}
+}
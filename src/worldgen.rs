@@ -1,39 +1,94 @@
 use std::{
-    collections::{HashMap, HashSet},
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
     fs::File,
     io::{Read, Seek, Write},
+    ops::Range,
     path::Path,
+    str::FromStr,
     sync::mpsc::Sender,
 };
 
-use anyhow::{Result, ensure};
-use fastanvil::Region;
+use anyhow::{Result, bail, ensure};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 use crate::{
-    chunk::{AIR, BlockState, Chunk},
+    chunk::{
+        AIR, BiomeSampling, BlockState, Chunk, ChunkFormat, ChunkPalette, ChunkStatus, MAX_LIGHT,
+        WATER, WATER_PLANTS,
+    },
     data::{
-        DH_SECTION_WIDTH, DHDataRequester, DHFullDataPoint, DHSectionData, DHSectionPos, RegionPos,
+        DH_SECTION_WIDTH, DHDataRequester, DHFullDataPoint, DHMappingEntry, DHSectionData,
+        DHSectionPos, RegionPos,
     },
+    region::{ChunkCompression, DEFAULT_COMPRESSION_LEVEL, RegionFormat, RegionWriter},
 };
 
-const Y_OFFSET: i32 = -64;
+const SEA_LEVEL: i32 = 63;
+const DEEPSLATE_TOP: i32 = 0;
+
+const NETHER_BIOMES: [&str; 5] = [
+    "minecraft:nether_wastes",
+    "minecraft:crimson_forest",
+    "minecraft:warped_forest",
+    "minecraft:soul_sand_valley",
+    "minecraft:basalt_deltas",
+];
+const END_BIOMES: [&str; 5] = [
+    "minecraft:the_end",
+    "minecraft:end_highlands",
+    "minecraft:end_midlands",
+    "minecraft:small_end_islands",
+    "minecraft:end_barrens",
+];
 
 // section_pos / 8 = region_pos
 pub const SECTION_REGION_SCALE: usize = 512 / DH_SECTION_WIDTH;
 
-const CHUNK_TEMP: &[u8] = include_bytes!("../chunk.nbt");
-
 pub enum WorldGenStatus {
     StartRegion { pos: RegionPos, thread_idx: usize },
     FinishDHSection { pos: DHSectionPos },
     FinishRegion { pos: RegionPos },
 }
 
+/// Settings for how DH data is turned into chunks.
+#[derive(Debug, Clone)]
+pub struct WorldGenOptions {
+    pub biome_sampling: BiomeSampling,
+    pub fluid_ticks: bool,
+    pub filler: Option<Filler>,
+    pub status: ChunkStatus,
+    pub format: ChunkFormat,
+    /// Replaces the empty chunk that every generated chunk starts from.
+    pub template: Option<Chunk>,
+    pub region_format: RegionFormat,
+    /// Only used for Anvil regions.
+    pub compression: ChunkCompression,
+    /// zlib or gzip level 0-9 for Anvil, zstd level for Linear.
+    pub compression_level: u32,
+}
+
+impl Default for WorldGenOptions {
+    fn default() -> Self {
+        WorldGenOptions {
+            biome_sampling: BiomeSampling::default(),
+            fluid_ticks: false,
+            filler: None,
+            status: ChunkStatus::Full,
+            format: ChunkFormat::default(),
+            template: None,
+            region_format: RegionFormat::Anvil,
+            compression: ChunkCompression::Zlib,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
 pub fn generate(
     region_poses: Vec<RegionPos>,
     section_requester: impl DHDataRequester + Send + Sync,
     out_dir: impl AsRef<Path>,
+    options: &WorldGenOptions,
     status_sender: Sender<WorldGenStatus>,
 ) -> Result<()> {
     ensure!(
@@ -42,7 +97,12 @@ pub fn generate(
         out_dir.as_ref().to_str().unwrap_or("None")
     );
     let out_dir = out_dir.as_ref().to_path_buf();
-    let temp_chunk = fastnbt::from_bytes::<Chunk>(CHUNK_TEMP)?;
+    let mut base_chunk = match &options.template {
+        Some(template) => template.clone(),
+        None => Chunk::new(0, 0, &options.format),
+    };
+    base_chunk.set_status(options.status);
+    base_chunk.set_biome_sampling(options.biome_sampling);
     let region_poses = region_poses.into_iter().collect::<HashSet<_>>();
     region_poses
         .into_par_iter()
@@ -51,7 +111,12 @@ pub fn generate(
             if dh_sections.is_empty() {
                 return Ok(());
             }
-            let region_file = out_dir.join(format!("r.{}.{}.mca", region_pos.x, region_pos.z));
+            let region_file = out_dir.join(format!(
+                "r.{}.{}.{}",
+                region_pos.x,
+                region_pos.z,
+                options.region_format.extension()
+            ));
             let region_file = File::options()
                 .read(true)
                 .write(true)
@@ -66,7 +131,8 @@ pub fn generate(
                 region_pos,
                 dh_sections,
                 &region_file,
-                &temp_chunk,
+                &base_chunk,
+                options,
                 &status_sender,
             )?;
             status_sender.send(WorldGenStatus::FinishRegion { pos: region_pos })?;
@@ -79,12 +145,18 @@ fn generate_region(
     region_pos: RegionPos,
     dh_sections: HashMap<DHSectionPos, DHSectionData>,
     stream: impl Read + Write + Seek,
-    chunk_temp: &Chunk,
+    base_chunk: &Chunk,
+    options: &WorldGenOptions,
     status_sender: &Sender<WorldGenStatus>,
 ) -> Result<()> {
     let region_snapped_section_pos = DHSectionPos::from(region_pos);
 
-    let mut region = Region::create(stream)?;
+    let mut region = RegionWriter::create(
+        stream,
+        options.region_format,
+        options.compression,
+        options.compression_level,
+    )?;
     for region_oriented_section_x in 0..SECTION_REGION_SCALE {
         for region_oriented_section_z in 0..SECTION_REGION_SCALE {
             let section_pos = DHSectionPos {
@@ -97,27 +169,41 @@ fn generate_region(
                 status_sender.send(WorldGenStatus::FinishDHSection { pos: section_pos })?;
                 continue;
             };
+            let (palette, mapping_ids) = resolve_mapping(dh_section, options);
             // Chunks in current section
-            let mut chunks = init_section_chunks(chunk_temp, &section_pos);
+            let mut chunks = init_section_chunks(base_chunk, &section_pos);
+            // Sections saved without light are left for the game to light on load.
+            let write_light = options.status.has_light()
+                && dh_section
+                    .data
+                    .iter()
+                    .flatten()
+                    .any(|data_point| data_point.block_light != 0 || data_point.sky_light != 0);
+            if !write_light {
+                for chunk in &mut chunks {
+                    chunk.is_light_on = false;
+                }
+            }
             for x in 0..DH_SECTION_WIDTH {
                 for z in 0..DH_SECTION_WIDTH {
                     let chunk = &mut chunks[(x & 0x30) >> 2 | (z & 0x30) >> 4];
                     let data_points = &dh_section.data[x * DH_SECTION_WIDTH + z];
-                    for data_point in data_points {
-                        let (block, biome) = get_block_biome(data_point, dh_section);
-                        for y in data_point.min_y..data_point.min_y + data_point.height {
-                            chunk.set_block_biome(
-                                x as u32 & 0xf,
-                                (y + Y_OFFSET).min(319),
-                                z as u32 & 0xf,
-                                block.clone(),
-                                biome.clone(),
-                            )?;
-                        }
+                    fill_dh_column(
+                        chunk,
+                        x as u32 & 0xf,
+                        z as u32 & 0xf,
+                        data_points,
+                        &mapping_ids,
+                        &palette,
+                        options,
+                    )?;
+                    if write_light {
+                        fill_dh_light(chunk, x as u32 & 0xf, z as u32 & 0xf, data_points, options);
                     }
                 }
             }
-            for (i, chunk) in chunks.iter().enumerate().take(16) {
+            for (i, chunk) in chunks.iter_mut().enumerate().take(16) {
+                chunk.update_heightmaps(options.status);
                 let chunk = &fastnbt::to_bytes(&chunk)?;
                 region.write_chunk(
                     (region_oriented_section_x * 4 + (i >> 2)) & 0x1ff,
@@ -128,25 +214,308 @@ fn generate_region(
             status_sender.send(WorldGenStatus::FinishDHSection { pos: section_pos })?;
         }
     }
+    region.finish()
+}
+
+/// Block storage that DH columns are reconstructed into.
+pub trait ColumnTarget {
+    /// Fills the blocks at `x`, `z` over the block height range `ys` with ids of `palette`.
+    fn fill_column(
+        &mut self,
+        x: u32,
+        z: u32,
+        ys: Range<i32>,
+        block: u16,
+        biome: u16,
+        palette: &ChunkPalette,
+    ) -> Result<()>;
+
+    /// Schedules a tick for `fluid` at `x`, `z` and the block height `y`.
+    fn schedule_fluid_tick(&mut self, x: u32, y: i32, z: u32, fluid: &str);
+}
+
+impl ColumnTarget for Chunk {
+    fn fill_column(
+        &mut self,
+        x: u32,
+        z: u32,
+        ys: Range<i32>,
+        block: u16,
+        biome: u16,
+        palette: &ChunkPalette,
+    ) -> Result<()> {
+        Chunk::fill_column(self, x, z, ys, block, biome, palette)
+    }
+
+    fn schedule_fluid_tick(&mut self, x: u32, y: i32, z: u32, fluid: &str) {
+        Chunk::schedule_fluid_tick(self, x, y, z, fluid);
+    }
+}
+
+/// Fills one column of `target` from the DH data points of that column.
+///
+/// Blocks with a `waterlogged` state get `waterlogged=true` when they sit right under water,
+/// and gaps between data points under water and below sea level are filled with water.
+pub fn fill_dh_column(
+    target: &mut impl ColumnTarget,
+    x: u32,
+    z: u32,
+    data_points: &[DHFullDataPoint],
+    mapping_ids: &[MappingIds],
+    palette: &ChunkPalette,
+    options: &WorldGenOptions,
+) -> Result<()> {
+    let mut data_points = data_points.iter().collect::<Vec<_>>();
+    data_points.sort_by_key(|data_point| Reverse(data_point.min_y));
+    let mut water_above = None;
+    let mut prev_min_y = None;
+    let mut lowest_filler = None;
+    for data_point in data_points {
+        let ids = &mapping_ids[data_point.id as usize];
+        let min_y = data_point.min_y + options.format.min_y;
+        let max_y = min_y + data_point.height;
+        if let (Some(water), Some(prev_min_y)) = (water_above, prev_min_y)
+            && max_y < prev_min_y
+        {
+            if prev_min_y <= SEA_LEVEL + 1 {
+                target.fill_column(x, z, max_y..prev_min_y, water, ids.biome, palette)?;
+                if options.fluid_ticks {
+                    target.schedule_fluid_tick(x, prev_min_y - 1, z, WATER);
+                }
+            } else {
+                water_above = None;
+            }
+        }
+        let block = match (ids.fluid, water_above) {
+            (Fluid::Waterloggable { waterlogged }, Some(_)) => {
+                if options.fluid_ticks {
+                    target.schedule_fluid_tick(x, max_y - 1, z, WATER);
+                }
+                waterlogged
+            }
+            _ => ids.block,
+        };
+        target.fill_column(x, z, min_y..max_y, block, ids.biome, palette)?;
+        water_above = match ids.fluid {
+            Fluid::Water { water } => Some(water),
+            Fluid::Waterloggable { .. } => water_above,
+            Fluid::None => None,
+        };
+        prev_min_y = Some(min_y);
+        lowest_filler = ids.filler.map(|filler| (filler, ids.biome));
+    }
+    // Fill the space under the lowest data point down to a bedrock floor.
+    let bottom = options.format.min_y;
+    if let (Some((filler, biome)), Some(top)) = (lowest_filler, prev_min_y)
+        && bottom < top
+    {
+        target.fill_column(x, z, bottom..bottom + 1, filler.floor, biome, palette)?;
+        let split = DEEPSLATE_TOP.clamp(bottom + 1, top);
+        target.fill_column(x, z, bottom + 1..split, filler.lower, biome, palette)?;
+        target.fill_column(x, z, split..top, filler.upper, biome, palette)?;
+    }
     Ok(())
 }
 
-fn init_section_chunks(chunk_temp: &Chunk, pos: &DHSectionPos) -> Vec<Chunk> {
+/// Lights one column of `chunk` with the light stored in its DH data points.
+///
+/// Gaps between data points take the light of the data point above them, the space above
+/// the highest one is lit by the sky and the space under the lowest one is left dark.
+fn fill_dh_light(
+    chunk: &mut Chunk,
+    x: u32,
+    z: u32,
+    data_points: &[DHFullDataPoint],
+    options: &WorldGenOptions,
+) {
+    let mut data_points = data_points.iter().collect::<Vec<_>>();
+    data_points.sort_by_key(|data_point| Reverse(data_point.min_y));
+    let format = &options.format;
+    let top = format.min_y + format.section_count as i32 * 16;
+    if data_points.is_empty() {
+        chunk.fill_light(x, z, format.min_y..top, 0, MAX_LIGHT);
+        return;
+    }
+    let mut above = (top, 0, MAX_LIGHT);
+    for data_point in data_points {
+        let min_y = data_point.min_y + format.min_y;
+        let max_y = min_y + data_point.height;
+        let (above_min_y, block_light, sky_light) = above;
+        chunk.fill_light(x, z, max_y..above_min_y, block_light, sky_light);
+        chunk.fill_light(
+            x,
+            z,
+            min_y..max_y,
+            data_point.block_light,
+            data_point.sky_light,
+        );
+        above = (min_y, data_point.block_light, data_point.sky_light);
+    }
+}
+
+fn init_section_chunks(base_chunk: &Chunk, pos: &DHSectionPos) -> Vec<Chunk> {
     let mut chunks = Vec::with_capacity(16);
     for i in 0..16 {
-        let mut chunk = chunk_temp.clone();
+        let mut chunk = base_chunk.clone();
         chunk.set_chunk_pos(&(pos.x) * 4 + (i >> 2), &(pos.z) * 4 + (i & 3));
-        chunk.set_status("minecraft:initialize_light".to_string());
         chunks.push(chunk);
     }
     chunks
 }
 
-fn get_block_biome(
-    data_point: &DHFullDataPoint,
+#[derive(Debug, Clone, Copy)]
+pub struct MappingIds {
+    block: u16,
+    biome: u16,
+    fluid: Fluid,
+    /// What to fill under this entry when it is the lowest of a column, `None` to leave it empty.
+    filler: Option<FillerIds>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FillerIds {
+    /// Filler from `DEEPSLATE_TOP` up.
+    upper: u16,
+    /// Filler below `DEEPSLATE_TOP`.
+    lower: u16,
+    /// The bottom layer of the world.
+    floor: u16,
+}
+
+/// Material for the space between the world bottom and the lowest DH data point of a column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filler {
+    /// Stone, with deepslate below y=0, or netherrack in the Nether. The End is left empty.
+    Auto,
+    /// One block id for every biome.
+    Block(String),
+}
+
+impl FromStr for Filler {
+    type Err = anyhow::Error;
+
+    /// Parses `auto` or a namespaced block id like `minecraft:stone`.
+    /// Only the form of the id is checked, the game turns unknown blocks into air.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Filler::Auto);
+        }
+        let is_id_part = |part: &str, extra: &[char]| {
+            !part.is_empty()
+                && part.chars().all(|c| {
+                    c.is_ascii_lowercase()
+                        || c.is_ascii_digit()
+                        || "_-.".contains(c)
+                        || extra.contains(&c)
+                })
+        };
+        match s.split_once(':') {
+            Some((namespace, path)) if is_id_part(namespace, &[]) && is_id_part(path, &['/']) => {
+                Ok(Filler::Block(s.to_string()))
+            }
+            _ => bail!(
+                "'{s}' is not a block id, expected `auto` or a lowercase `namespace:path` like `minecraft:stone`"
+            ),
+        }
+    }
+}
+
+fn resolve_filler(
+    filler: &Filler,
+    block: &BlockState,
+    biome: &str,
+    palette: &mut ChunkPalette,
+) -> Option<FillerIds> {
+    if block.name == AIR {
+        return None;
+    }
+    let mut block_id = |name: &str| {
+        palette.block_id(BlockState {
+            name: name.to_string(),
+            properties: None,
+        })
+    };
+    let (upper, lower) = match filler {
+        Filler::Block(name) => (block_id(name), block_id(name)),
+        Filler::Auto if NETHER_BIOMES.contains(&biome) => (
+            block_id("minecraft:netherrack"),
+            block_id("minecraft:netherrack"),
+        ),
+        Filler::Auto if END_BIOMES.contains(&biome) => return None,
+        Filler::Auto => (block_id("minecraft:stone"), block_id("minecraft:deepslate")),
+    };
+    Some(FillerIds {
+        upper,
+        lower,
+        floor: block_id("minecraft:bedrock"),
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Fluid {
+    None,
+    /// Water, or a block that always holds water. `water` is the id of plain water.
+    Water {
+        water: u16,
+    },
+    /// A dry block that has a `waterlogged` state. `waterlogged` is the id of its wet variant.
+    Waterloggable {
+        waterlogged: u16,
+    },
+}
+
+/// Interns every mapping entry of `dh_section` into a new palette.
+/// Returns the palette and the ids for each mapping id.
+pub fn resolve_mapping(
     dh_section: &DHSectionData,
-) -> (BlockState, String) {
-    let mapping = &dh_section.mapping[data_point.id as usize];
+    options: &WorldGenOptions,
+) -> (ChunkPalette, Vec<MappingIds>) {
+    let mut palette = ChunkPalette::default();
+    let water = palette.block_id(BlockState {
+        name: WATER.to_string(),
+        properties: Some(BTreeMap::from([("level".to_string(), "0".to_string())])),
+    });
+    let mapping_ids = dh_section
+        .mapping
+        .iter()
+        .map(|mapping| {
+            let (block, biome) = get_block_biome(mapping);
+            let waterlogged = block
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.get("waterlogged"));
+            let fluid = if block.name == WATER
+                || WATER_PLANTS.contains(&block.name.as_str())
+                || waterlogged.is_some_and(|waterlogged| waterlogged == "true")
+            {
+                Fluid::Water { water }
+            } else if waterlogged.is_some() {
+                let mut waterlogged = block.clone();
+                if let Some(properties) = &mut waterlogged.properties {
+                    properties.insert("waterlogged".to_string(), "true".to_string());
+                }
+                Fluid::Waterloggable {
+                    waterlogged: palette.block_id(waterlogged),
+                }
+            } else {
+                Fluid::None
+            };
+            let filler = options
+                .filler
+                .as_ref()
+                .and_then(|filler| resolve_filler(filler, &block, biome, &mut palette));
+            MappingIds {
+                block: palette.block_id(block),
+                biome: palette.biome_id(biome),
+                fluid,
+                filler,
+            }
+        })
+        .collect();
+    (palette, mapping_ids)
+}
+
+fn get_block_biome(mapping: &DHMappingEntry) -> (BlockState, &str) {
     let block = mapping.block.clone();
     let state = &mapping.block_state;
     let block_state = BlockState {
@@ -157,6 +526,31 @@ fn get_block_biome(
             None
         },
     };
-    let biome = mapping.biome.clone();
-    (block_state, biome)
+    (block_state, &mapping.biome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filler_must_be_a_namespaced_block_id() {
+        assert_eq!("auto".parse::<Filler>().unwrap(), Filler::Auto);
+        for id in [
+            "minecraft:stone",
+            "create:andesite/cut_polished",
+            "mod-x:a.b_c",
+        ] {
+            assert_eq!(id.parse::<Filler>().unwrap(), Filler::Block(id.to_string()));
+        }
+        for id in [
+            "stone",
+            "Minecraft:Stone",
+            "minecraft:",
+            ":stone",
+            "minecraft:stone[a=b]",
+        ] {
+            assert!(id.parse::<Filler>().is_err(), "{id}");
+        }
+    }
 }